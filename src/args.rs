@@ -1,5 +1,7 @@
 use clap::{Parser, Subcommand};
 
+use crate::auth::Scope;
+
 #[derive(Parser)]
 #[command(author="Eslzzyl")]
 #[command(version)]
@@ -11,6 +13,71 @@ pub struct Cli {
 
 #[derive(Subcommand)]
 pub enum Commands {
-    /// Delete all the files in file storage
+    /// Delete all the files in file storage (move them to trash if it is enabled)
     Clear,
+    /// List groups of near-duplicate images
+    Duplicates {
+        /// Maximum Hamming distance between perceptual hashes of similar images (0-64)
+        #[arg(short, long, default_value_t = 5)]
+        distance: u32,
+    },
+    /// List uploaded files, newest first
+    List {
+        /// Number of files to skip
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+        /// Maximum number of files to list
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: u64,
+    },
+    /// Move local files into the directory layout set by `shard_levels`
+    Migrate,
+    /// Manage deleted files in trash
+    Trash {
+        #[command(subcommand)]
+        command: TrashCommands,
+    },
+    /// Manage API tokens stored in the metadata database
+    Token {
+        #[command(subcommand)]
+        command: TokenCommands,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TrashCommands {
+    /// List files in trash, most recently deleted first
+    List,
+    /// Restore a file from trash
+    Restore {
+        /// Name of the file, e.g. abcdef.png
+        name: String,
+    },
+    /// Permanently delete all the files in trash
+    Empty,
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommands {
+    /// Generate a new token and print it
+    Add {
+        /// Name of the token, recorded with the files uploaded with it
+        name: String,
+        /// Operation the token is allowed to do, can be repeated
+        #[arg(short, long = "scope", value_enum, required = true)]
+        scopes: Vec<Scope>,
+        /// Seconds until the token expires, never expires if omitted
+        #[arg(long)]
+        expires_in: Option<u64>,
+        /// Maximum size in MB of files uploaded with the token
+        #[arg(long)]
+        max_file_size: Option<usize>,
+    },
+    /// List tokens in config and in the metadata database
+    List,
+    /// Revoke a token in the metadata database
+    Revoke {
+        /// Name of the token
+        name: String,
+    },
 }
\ No newline at end of file
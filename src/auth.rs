@@ -0,0 +1,151 @@
+use std::{fmt, io, str::FromStr};
+
+use serde_derive::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+use crate::store::MetaStore;
+use crate::util::{get_str_sha256, get_time};
+
+/// 一个token可以进行的操作
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Scope {
+    /// 上传文件
+    Upload,
+    /// 不提供删除密钥直接删除任何文件
+    Delete,
+    /// 查看上传记录、相似图片和回收站
+    List,
+    /// 包括以上所有操作，以及从回收站恢复文件
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Upload => "Upload",
+            Scope::Delete => "Delete",
+            Scope::List => "List",
+            Scope::Admin => "Admin",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Upload" => Ok(Scope::Upload),
+            "Delete" => Ok(Scope::Delete),
+            "List" => Ok(Scope::List),
+            "Admin" => Ok(Scope::Admin),
+            _ => Err(format!("unknown scope {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// # TokenConfig
+///
+/// 配置文件中的一个token
+///
+/// - `name`: token的名称，会被记录在通过它上传的文件的上传记录中
+/// - `token`: token本身
+/// - `scopes`: 允许的操作
+/// - `expires_at`: 过期时间，Unix时间戳，可省略，省略时永不过期
+/// - `max_file_size`: 通过它上传的文件的大小上限，单位为MB，可省略，省略时使用全局的`max_file_size`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenConfig {
+    pub name: String,
+    pub token: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub max_file_size: Option<usize>,
+}
+
+/// # ApiToken
+///
+/// 验证通过的token的信息，不含token本身
+#[derive(Serialize, Debug, Clone)]
+pub struct ApiToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// 过期时间，Unix时间戳，永不过期时为`None`
+    pub expires_at: Option<u64>,
+    /// 通过它上传的文件的大小上限，单位为字节，为`None`时使用全局的上限
+    pub max_file_size: Option<usize>,
+}
+
+impl ApiToken {
+    /// 是否允许进行`scope`操作，`Admin`允许所有操作
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    /// 在`now`时是否已经过期
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// # Tokens
+///
+/// 所有可用的token，包括配置文件中的和通过命令行添加到元数据库中的。
+///
+/// 配置文件中的token在启动时读入内存，只保存哈希；元数据库中的token每次验证时查询，添加和吊销后立即生效。
+#[derive(Clone)]
+pub struct Tokens {
+    /// 配置文件中的token和它们的哈希
+    configured: Vec<(String, ApiToken)>,
+    store: MetaStore,
+}
+
+impl Tokens {
+    pub fn new(configured: Vec<TokenConfig>, store: MetaStore) -> Self {
+        let configured = configured
+            .into_iter()
+            .map(|config| {
+                let token = ApiToken {
+                    name: config.name,
+                    scopes: config.scopes,
+                    expires_at: config.expires_at,
+                    max_file_size: config.max_file_size,
+                };
+                (get_str_sha256(&config.token), token)
+            })
+            .collect();
+        Self { configured, store }
+    }
+
+    /// # authenticate
+    ///
+    /// 查找`token`对应的token信息，不存在或者已经过期时返回`None`
+    ///
+    /// 配置文件中的token逐个以常量时间比较哈希，比较所用的时间与token是否正确无关。
+    /// 元数据库中的token按哈希查找，查找时间只与哈希有关，不能用来逐位猜测token。
+    pub async fn authenticate(&self, token: &str) -> io::Result<Option<ApiToken>> {
+        let token_hash = get_str_sha256(token);
+        let mut found = None;
+        for (hash, configured) in &self.configured {
+            if bool::from(hash.as_bytes().ct_eq(token_hash.as_bytes())) {
+                found = Some(configured.clone());
+            }
+        }
+        if found.is_none() {
+            found = self.store.token(&token_hash).await?;
+        }
+        Ok(found.filter(|found| !found.is_expired(get_time())))
+    }
+
+    /// 配置文件中的token的名称
+    pub fn configured_names(&self) -> Vec<String> {
+        self.configured.iter().map(|(_, token)| token.name.clone()).collect()
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use actix_web::web::{self, Bytes};
+use log::{info, warn};
+use lru::LruCache;
+use tempfile::NamedTempFile;
+
+/// # RenditionCache
+///
+/// 缩放、转换后的图片的磁盘缓存。
+///
+/// 每个原图的派生版本存放在以原图文件名命名的子目录中，即`cache/{原图}/{变换参数}`，
+/// 删除原图时整个子目录一起删除。缓存总大小超过上限时按最近最少使用的顺序淘汰。
+pub struct RenditionCache {
+    root: PathBuf,
+    max_size: u64,
+    index: Mutex<CacheIndex>,
+}
+
+struct CacheIndex {
+    /// 键为`{原图}/{变换参数}`，值为文件大小
+    entries: LruCache<String, u64>,
+    total_size: u64,
+}
+
+impl RenditionCache {
+    /// 以`root`为缓存目录创建缓存，并从目录中已有的文件恢复索引
+    ///
+    /// 已有文件按修改时间排序，较新的视为较近使用过。
+    pub async fn new(root: impl Into<PathBuf>, max_size: u64) -> io::Result<Self> {
+        let root = root.into();
+        let scan_root = root.clone();
+        let files = web::block(move || scan(&scan_root))
+            .await
+            .map_err(io::Error::other)??;
+
+        let mut index = CacheIndex {
+            entries: LruCache::unbounded(),
+            total_size: 0,
+        };
+        for (_, key, size) in files {
+            index.total_size += size;
+            index.entries.put(key, size);
+        }
+        info!(
+            "Rendition cache: {} file(s), {} byte(s).",
+            index.entries.len(),
+            index.total_size
+        );
+
+        let cache = Self {
+            root,
+            max_size,
+            index: Mutex::new(index),
+        };
+        cache.evict().await;
+        Ok(cache)
+    }
+
+    /// 读取原图`source`按`key`变换后的版本，不存在时返回`None`
+    pub async fn get(&self, source: &str, key: &str) -> Option<Bytes> {
+        let cache_key = format!("{}/{}", source, key);
+        // 查询的同时把这一项标记为最近使用
+        self.index.lock().unwrap().entries.get(&cache_key)?;
+        match tokio::fs::read(self.root.join(&cache_key)).await {
+            Ok(content) => Some(Bytes::from(content)),
+            Err(e) => {
+                // 文件可能被其他进程（例如Clear命令）删掉了，同步一下索引
+                warn!("Cached rendition {} unreadable: {}", &cache_key, e);
+                self.forget(|k| k == cache_key);
+                None
+            }
+        }
+    }
+
+    /// 写入原图`source`按`key`变换后的版本，必要时淘汰旧的缓存
+    pub async fn put(&self, source: &str, key: &str, content: Bytes) -> io::Result<()> {
+        let size = content.len() as u64;
+        if size > self.max_size {
+            return Ok(());
+        }
+        let dir = self.root.join(source);
+        let target = dir.join(key);
+        web::block(move || {
+            std::fs::create_dir_all(&dir)?;
+            // 先写临时文件再重命名，避免并发读到写了一半的内容；
+            // 临时文件名是随机的，同时写入同一个版本也不会互相覆盖
+            let mut temp_file = NamedTempFile::new_in(&dir)?;
+            temp_file.write_all(&content)?;
+            temp_file.persist(target)?;
+            Ok::<_, io::Error>(())
+        })
+        .await
+        .map_err(io::Error::other)??;
+
+        {
+            let mut index = self.index.lock().unwrap();
+            if let Some(old_size) = index.entries.put(format!("{}/{}", source, key), size) {
+                index.total_size -= old_size;
+            }
+            index.total_size += size;
+        }
+        self.evict().await;
+        Ok(())
+    }
+
+    /// 删除原图`source`的所有派生版本
+    pub async fn remove_source(&self, source: &str) -> io::Result<()> {
+        let prefix = format!("{}/", source);
+        self.forget(|k| k.starts_with(&prefix));
+        match tokio::fs::remove_dir_all(self.root.join(source)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// 清空整个缓存
+    pub async fn clear(&self) -> io::Result<()> {
+        self.forget(|_| true);
+        let root = self.root.clone();
+        web::block(move || {
+            for entry in std::fs::read_dir(&root)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    std::fs::remove_dir_all(path)?;
+                } else {
+                    std::fs::remove_file(path)?;
+                }
+            }
+            Ok(())
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    /// 从索引中移除满足条件的项
+    fn forget(&self, predicate: impl Fn(&str) -> bool) {
+        let mut index = self.index.lock().unwrap();
+        let keys: Vec<String> = index
+            .entries
+            .iter()
+            .filter(|(k, _)| predicate(k))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in keys {
+            if let Some(size) = index.entries.pop(&key) {
+                index.total_size -= size;
+            }
+        }
+    }
+
+    /// 淘汰最近最少使用的缓存，直到总大小不超过上限
+    async fn evict(&self) {
+        let mut evicted = Vec::new();
+        {
+            let mut index = self.index.lock().unwrap();
+            while index.total_size > self.max_size {
+                match index.entries.pop_lru() {
+                    Some((key, size)) => {
+                        index.total_size -= size;
+                        evicted.push(key);
+                    }
+                    None => break,
+                }
+            }
+        }
+        if evicted.is_empty() {
+            return;
+        }
+        let root = self.root.clone();
+        // 空的子目录留着，删掉它会和同时写入这个原图的`put`冲突；删除原图时会一起删掉
+        let removed = web::block(move || {
+            for key in evicted {
+                if let Err(e) = std::fs::remove_file(root.join(&key)) {
+                    warn!("Error evicting cached rendition {}: {}", &key, e);
+                }
+            }
+        })
+        .await;
+        if let Err(e) = removed {
+            warn!("Error evicting cached renditions: {}", e);
+        }
+    }
+}
+
+/// 扫描缓存目录中已有的文件，按修改时间从旧到新排序
+fn scan(root: &Path) -> io::Result<Vec<(SystemTime, String, u64)>> {
+    std::fs::create_dir_all(root)?;
+    let mut files = Vec::new();
+    for source in std::fs::read_dir(root)? {
+        let source = source?;
+        if !source.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(source.path())? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            // 以`.`开头的是写了一半的临时文件
+            if !metadata.is_file() || entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let key = format!(
+                "{}/{}",
+                source.file_name().to_string_lossy(),
+                entry.file_name().to_string_lossy()
+            );
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((modified, key, metadata.len()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
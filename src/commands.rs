@@ -1,22 +1,40 @@
-use std::fs;
 use std::io::{self, Write};
 
+use crate::auth::{ApiToken, Scope};
+use crate::cache::RenditionCache;
 use crate::config::Config;
+use crate::imaging;
+use crate::name::StoredName;
+use crate::storage::{self, LocalStorage, StorageBackend};
+use crate::store::MetaStore;
+use crate::trash::Trash;
+use crate::util::{format_file_size, generate_key, get_str_sha256, get_time};
 
-pub fn clear_storage() {
+/// 按配置打开回收站，不使用回收站时返回`None`
+fn open_trash(config: &Config) -> Option<Trash> {
+    if config.trash_retention() == 0 {
+        return None;
+    }
+    Some(Trash::new(
+        storage::from_config(config).unwrap(),
+        storage::trash_from_config(config).unwrap(),
+        MetaStore::open(format!("{}/meta.db", config.www_root())).unwrap(),
+        config.trash_retention(),
+    ))
+}
+
+pub async fn clear_storage() {
     let config = Config::from_toml("config/config.toml");
-    let www_root = config.www_root();
-    let storage_path = format!("{}/file/", www_root);
+    let storage = storage::from_config(&config).unwrap();
 
-    let mut count = 0u64;
-    let entries = fs::read_dir(&storage_path).unwrap();
-    for entry in entries {
-        if entry.unwrap().path().is_file() {
-            count += 1;
-        }
-    }
+    let names = storage.list().await.unwrap();
+    let count = names.len();
+    let trash = open_trash(&config);
 
-    println!("This command will clear ALL the files in {}. The action is irreversible!", &storage_path);
+    match trash {
+        Some(_) => println!("This command will move ALL the files in {:?} storage to trash.", config.storage()),
+        None => println!("This command will clear ALL the files in {:?} storage. The action is irreversible!", config.storage()),
+    }
     println!("There are {} file(s) in total.", count);
     print!("Do you want to proceed? [y/N]");
     io::stdout().flush().unwrap();
@@ -25,26 +43,225 @@ pub fn clear_storage() {
     io::stdin().read_line(&mut input).expect("error when reading input.");
     let first_char = input.trim().chars().next();
     match first_char {
-        Some(c) => {
-            if c == 'y' {
-                let entries = fs::read_dir(&storage_path).unwrap();
-    
-                for entry in entries {
-                    let path = entry.unwrap().path();
-                    if path.is_file() {
-                        fs::remove_file(path).unwrap();
+        Some('y') => {
+            for name in &names {
+                match &trash {
+                    Some(trash) => {
+                        trash.put(name).await.unwrap();
                     }
+                    None => storage.delete(name).await.unwrap(),
                 }
+            }
+            let cache_dir = format!("{}/cache", config.www_root());
+            RenditionCache::new(cache_dir, config.rendition_cache_size())
+                .await
+                .unwrap()
+                .clear()
+                .await
+                .unwrap();
+            // 回收站中的文件还需要它们的记录
+            if trash.is_none() {
+                MetaStore::open(format!("{}/meta.db", config.www_root()))
+                    .unwrap()
+                    .clear()
+                    .await
+                    .unwrap();
+            }
 
-                println!("Removed {} files.", count);
-            } else {
-                println!("Abort.");
-                return;
+            match trash {
+                Some(_) => println!("Moved {} files to trash.", count),
+                None => println!("Removed {} files.", count),
             }
         },
-        None => {
+        _ => {
             println!("Abort.");
+        }
+    }
+}
+
+pub async fn list_duplicates(distance: u32) {
+    let config = Config::from_toml("config/config.toml");
+    let store = MetaStore::open(format!("{}/meta.db", config.www_root())).unwrap();
+
+    let hashes = store.perceptual_hashes().await.unwrap();
+    let groups = imaging::group_similar(&hashes, distance.min(64));
+
+    println!("Checked {} image(s), found {} group(s) of near-duplicates within distance {}.", hashes.len(), groups.len(), distance);
+    for (i, group) in groups.iter().enumerate() {
+        println!("Group {}:", i + 1);
+        for name in group {
+            println!("  {}", name);
+        }
+    }
+}
+
+pub async fn list_files(offset: u64, limit: u64) {
+    let config = Config::from_toml("config/config.toml");
+    let store = MetaStore::open(format!("{}/meta.db", config.www_root())).unwrap();
+
+    let (count, size) = store.totals().await.unwrap();
+    println!("{} file(s), {} in total.", count, format_file_size(size as usize));
+    for record in store.list_files(offset, limit).await.unwrap() {
+        let dimensions = match (record.width, record.height) {
+            (Some(width), Some(height)) => format!("{}x{}", width, height),
+            _ => "-".to_string(),
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            record.name,
+            record.uploaded_at,
+            format_file_size(record.size as usize),
+            record.mime,
+            dimensions,
+            record.uploader.as_deref().unwrap_or("-"),
+            record.original_name.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+pub async fn migrate_layout() {
+    let config = Config::from_toml("config/config.toml");
+    if config.storage() != StorageBackend::Local {
+        println!("Only the local storage has a directory layout, nothing to do.");
+        return;
+    }
+
+    let root = format!("{}/file", config.www_root());
+    let storage = LocalStorage::sharded(&root, config.shard_levels()).unwrap();
+    println!("Moving files in {} into {} level(s) of subdirectories. Please stop the server first.", root, config.shard_levels());
+    let moved = storage.migrate().await.unwrap();
+    println!("Moved {} file(s).", moved);
+}
+
+pub async fn list_trash() {
+    let config = Config::from_toml("config/config.toml");
+    let Some(trash) = open_trash(&config) else {
+        println!("Trash is disabled.");
+        return;
+    };
+
+    let records = trash.list().await.unwrap();
+    println!("{} file(s) in trash, kept for {}s after deletion.", records.len(), trash.retention());
+    for record in records {
+        println!(
+            "{}\t{}\t{}\t{}",
+            record.name,
+            record.trashed_at.unwrap_or_default(),
+            format_file_size(record.size as usize),
+            record.original_name.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+pub async fn restore_from_trash(name: &str) {
+    let config = Config::from_toml("config/config.toml");
+    let Some(trash) = open_trash(&config) else {
+        println!("Trash is disabled.");
+        return;
+    };
+    let name = match name.parse::<StoredName>() {
+        Ok(name) => name,
+        Err(msg) => {
+            println!("{}", msg);
             return;
         }
+    };
+
+    match trash.restore(&name).await {
+        Ok(_) => println!("Restored {}.", name),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => println!("{} is not in trash.", name),
+        Err(e) => panic!("Error restoring {}: {}", name, e),
+    }
+}
+
+pub async fn empty_trash() {
+    let config = Config::from_toml("config/config.toml");
+    let Some(trash) = open_trash(&config) else {
+        println!("Trash is disabled.");
+        return;
+    };
+
+    let count = trash.list().await.unwrap().len();
+    println!("This command will permanently delete {} file(s) in trash. The action is irreversible!", count);
+    print!("Do you want to proceed? [y/N]");
+    io::stdout().flush().unwrap();
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).expect("error when reading input.");
+    match input.trim().chars().next() {
+        Some('y') => {
+            let purged = trash.empty().await.unwrap();
+            println!("Removed {} files.", purged);
+        }
+        _ => println!("Abort."),
+    }
+}
+
+pub async fn add_token(name: &str, scopes: &[Scope], expires_in: Option<u64>, max_file_size: Option<usize>) {
+    let config = Config::from_toml("config/config.toml");
+    if config.tokens().iter().any(|token| token.name == name) {
+        println!("Token {} already exists in config.", name);
+        return;
+    }
+    let store = MetaStore::open(format!("{}/meta.db", config.www_root())).unwrap();
+
+    let token = generate_key();
+    let api_token = ApiToken {
+        name: name.to_string(),
+        scopes: scopes.to_vec(),
+        expires_at: expires_in.map(|expires_in| get_time() + expires_in),
+        max_file_size: max_file_size.map(|size| size * 1024 * 1024),
+    };
+    if !store.add_token(api_token, &get_str_sha256(&token)).await.unwrap() {
+        println!("Token {} already exists.", name);
+        return;
+    }
+    println!("Added token {}. It is only shown once, please keep it safe:", name);
+    println!("{}", token);
+}
+
+pub async fn list_tokens() {
+    let config = Config::from_toml("config/config.toml");
+    let store = MetaStore::open(format!("{}/meta.db", config.www_root())).unwrap();
+
+    let configured = config.tokens().into_iter().map(|token| {
+        let api_token = ApiToken {
+            name: token.name,
+            scopes: token.scopes,
+            expires_at: token.expires_at,
+            max_file_size: token.max_file_size,
+        };
+        (api_token, "config")
+    });
+    let stored = store.tokens().await.unwrap().into_iter().map(|token| (token, "database"));
+    let now = get_time();
+    for (token, source) in configured.chain(stored) {
+        let scopes: Vec<&str> = token.scopes.iter().map(Scope::as_str).collect();
+        let expires_at = match token.expires_at {
+            Some(_) if token.is_expired(now) => "expired".to_string(),
+            Some(expires_at) => expires_at.to_string(),
+            None => "-".to_string(),
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            token.name,
+            scopes.join(","),
+            expires_at,
+            token.max_file_size.map_or("-".to_string(), format_file_size),
+            source,
+        );
+    }
+}
+
+pub async fn revoke_token(name: &str) {
+    let config = Config::from_toml("config/config.toml");
+    let store = MetaStore::open(format!("{}/meta.db", config.www_root())).unwrap();
+
+    if store.remove_token(name).await.unwrap() {
+        println!("Revoked token {}.", name);
+    } else if config.tokens().iter().any(|token| token.name == name) {
+        println!("Token {} is in config, remove it from config/config.toml instead.", name);
+    } else {
+        println!("Token {} not found.", name);
     }
-}
\ No newline at end of file
+}
@@ -4,7 +4,10 @@ use std::io::prelude::*;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 
-use crate::util::get_str_sha256;
+use crate::auth::{Scope, TokenConfig};
+use crate::imaging::NegotiatedFormat;
+use crate::policy::ActiveContentPolicy;
+use crate::storage::{S3Config, StorageBackend};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum UploadMode {
@@ -13,6 +16,16 @@ pub enum UploadMode {
     Blacklist,
 }
 
+/// 上传文件的真实类型与扩展名不一致时的处理方式
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum MismatchAction {
+    /// 拒绝上传
+    #[default]
+    Reject,
+    /// 把扩展名改为真实类型的扩展名
+    Rewrite,
+}
+
 /// # Config
 /// 
 /// 存储服务配置信息
@@ -32,10 +45,79 @@ pub struct Config {
     local: bool,
     max_file_size: usize,
     use_token: bool,
+    #[serde(default)]
     token: String,
+    #[serde(default)]
+    tokens: Vec<TokenConfig>,
     upload_mode: UploadMode,
     upload_whitelist: Vec<String>,
     upload_blacklist: Vec<String>,
+    #[serde(default)]
+    extension_mismatch: MismatchAction,
+    #[serde(default)]
+    active_content: ActiveContentPolicy,
+    #[serde(default)]
+    storage: StorageBackend,
+    #[serde(default)]
+    shard_levels: usize,
+    s3: Option<S3Config>,
+    #[serde(default = "default_cache_control")]
+    cache_control: String,
+    #[serde(default = "default_resize_max_dimension")]
+    resize_max_width: u32,
+    #[serde(default = "default_resize_max_dimension")]
+    resize_max_height: u32,
+    #[serde(default = "default_rendition_cache_size")]
+    rendition_cache_size: u64,
+    #[serde(default)]
+    thumbnails: Vec<u32>,
+    #[serde(default)]
+    negotiate_formats: Vec<NegotiatedFormat>,
+    #[serde(default)]
+    dedup: bool,
+    #[serde(default)]
+    auto_orient: bool,
+    #[serde(default)]
+    strip_metadata: bool,
+    #[serde(default = "default_true")]
+    keep_orientation: bool,
+    #[serde(default = "default_true")]
+    keep_color_profile: bool,
+    #[serde(default = "default_stats_reconcile_interval")]
+    stats_reconcile_interval: u64,
+    #[serde(default)]
+    default_expires_in: u64,
+    #[serde(default)]
+    max_expires_in: u64,
+    #[serde(default = "default_trash_retention")]
+    trash_retention: u64,
+}
+
+/// 本地存储最多使用的子目录级数
+const MAX_SHARD_LEVELS: usize = 4;
+
+fn default_cache_control() -> String {
+    "public, max-age=31536000, immutable".to_string()
+}
+
+fn default_resize_max_dimension() -> u32 {
+    2048
+}
+
+fn default_rendition_cache_size() -> u64 {
+    256
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_stats_reconcile_interval() -> u64 {
+    3600
+}
+
+fn default_trash_retention() -> u64 {
+    7 * 24 * 3600
 }
 
 impl Config {
@@ -51,9 +133,30 @@ impl Config {
             max_file_size: 5 * 1024 * 1024,
             use_token: false,
             token: "testtoken".to_string(),
+            tokens: Vec::new(),
             upload_mode: UploadMode::None,
             upload_whitelist: Vec::new(),
             upload_blacklist: Vec::new(),
+            extension_mismatch: MismatchAction::Reject,
+            active_content: ActiveContentPolicy::Attachment,
+            storage: StorageBackend::Local,
+            shard_levels: 0,
+            s3: None,
+            cache_control: default_cache_control(),
+            resize_max_width: default_resize_max_dimension(),
+            resize_max_height: default_resize_max_dimension(),
+            rendition_cache_size: default_rendition_cache_size() * 1024 * 1024,
+            thumbnails: Vec::new(),
+            negotiate_formats: Vec::new(),
+            dedup: false,
+            auto_orient: false,
+            strip_metadata: false,
+            keep_orientation: true,
+            keep_color_profile: true,
+            stats_reconcile_interval: default_stats_reconcile_interval(),
+            default_expires_in: 0,
+            max_expires_in: 0,
+            trash_retention: default_trash_retention(),
         }
     }
 
@@ -74,14 +177,21 @@ impl Config {
             Err(e) => panic!("Error Reading file: {}", e)
         };
         // 尝试读配置文件，若成功则返回，若失败则返回默认值
-        let mut raw_config = match toml::from_str(&str_val) {
+        // 配置文件中的大小以MB为单位，只换算读到的值，默认配置本身已经以字节为单位
+        let mut raw_config: Config = match toml::from_str(&str_val) {
             Ok(t) => t,
             Err(_) => {
                 println!("无法成功从配置文件构建配置对象，使用默认配置");
-                Config::new()
+                return Config::new();
             }
         };
         raw_config.max_file_size *= 1024 * 1024;
+        raw_config.rendition_cache_size *= 1024 * 1024;
+        for token in raw_config.tokens.iter_mut() {
+            if let Some(size) = token.max_file_size.as_mut() {
+                *size *= 1024 * 1024;
+            }
+        }
         raw_config
     }
 }
@@ -129,8 +239,23 @@ impl Config {
         self.use_token
     }
 
-    pub fn hashed_token(&self) -> String {
-        get_str_sha256(&self.token)
+    /// # tokens
+    ///
+    /// 配置文件中的所有token
+    ///
+    /// 开启了`use_token`并且`token`不为空时，`token`作为一个名为`default`、拥有所有权限的token一并返回。
+    pub fn tokens(&self) -> Vec<TokenConfig> {
+        let mut tokens = self.tokens.clone();
+        if self.use_token && !self.token.is_empty() {
+            tokens.push(TokenConfig {
+                name: "default".to_string(),
+                token: self.token.clone(),
+                scopes: vec![Scope::Admin],
+                expires_at: None,
+                max_file_size: None,
+            });
+        }
+        tokens
     }
 
     pub fn upload_mode(&self) -> UploadMode {
@@ -144,4 +269,112 @@ impl Config {
     pub fn upload_blacklist(&self) -> Vec<String> {
         self.upload_blacklist.clone()
     }
+
+    /// 上传文件的真实类型与扩展名不一致时的处理方式
+    pub fn extension_mismatch(&self) -> MismatchAction {
+        self.extension_mismatch.clone()
+    }
+
+    /// 可能包含脚本的文件（HTML、SVG等）的处理策略
+    pub fn active_content(&self) -> ActiveContentPolicy {
+        self.active_content
+    }
+
+    /// 使用的存储后端
+    pub fn storage(&self) -> StorageBackend {
+        self.storage.clone()
+    }
+
+    /// 访问存储中的文件时返回的`Cache-Control`头
+    ///
+    /// 文件名由内容哈希得到，同名文件的内容不会变化，因此默认允许客户端永久缓存。
+    pub fn cache_control(&self) -> &str {
+        &self.cache_control
+    }
+
+    /// 通过查询参数缩放图片时允许的最大宽度
+    pub fn resize_max_width(&self) -> u32 {
+        self.resize_max_width
+    }
+
+    /// 通过查询参数缩放图片时允许的最大高度
+    pub fn resize_max_height(&self) -> u32 {
+        self.resize_max_height
+    }
+
+    /// 缩放后的图片的磁盘缓存上限，单位为字节
+    pub fn rendition_cache_size(&self) -> u64 {
+        self.rendition_cache_size
+    }
+
+    /// 上传图片时预先生成的缩略图尺寸
+    ///
+    /// 每个尺寸`n`生成一张缩放到`n`x`n`之内的缩略图。
+    pub fn thumbnails(&self) -> Vec<u32> {
+        self.thumbnails.clone()
+    }
+
+    /// 按`Accept`头协商返回的图片格式，按优先级排列，为空时不做协商
+    pub fn negotiate_formats(&self) -> Vec<NegotiatedFormat> {
+        self.negotiate_formats.clone()
+    }
+
+    /// 是否对上传的文件去重
+    ///
+    /// 开启后文件名只由内容决定，相同的文件只保存一份，并记录被上传的次数。
+    pub fn dedup(&self) -> bool {
+        self.dedup
+    }
+
+    /// 上传时是否按EXIF中的方向旋转图片，并把方向重置为正常
+    pub fn auto_orient(&self) -> bool {
+        self.auto_orient
+    }
+
+    /// 上传时是否去除JPEG/PNG/WebP中的EXIF、XMP、IPTC等元数据
+    pub fn strip_metadata(&self) -> bool {
+        self.strip_metadata
+    }
+
+    /// 去除元数据时是否保留EXIF中的方向
+    pub fn keep_orientation(&self) -> bool {
+        self.keep_orientation
+    }
+
+    /// 去除元数据时是否保留ICC色彩配置文件
+    pub fn keep_color_profile(&self) -> bool {
+        self.keep_color_profile
+    }
+
+    /// 扫描存储校正文件数和总大小的间隔，单位为秒，为0时不校正
+    pub fn stats_reconcile_interval(&self) -> u64 {
+        self.stats_reconcile_interval
+    }
+
+    /// 本地存储按文件名前缀分散文件的子目录级数，为0时平铺，最多4级
+    pub fn shard_levels(&self) -> usize {
+        self.shard_levels.min(MAX_SHARD_LEVELS)
+    }
+
+    /// 上传时没有指定`expires_in`时文件的有效期，单位为秒，为0时永不过期
+    pub fn default_expires_in(&self) -> u64 {
+        self.default_expires_in
+    }
+
+    /// 文件的最长有效期，单位为秒，为0时不限制
+    ///
+    /// 设置后所有文件都会过期，超过这个值的`expires_in`会被缩短到这个值。
+    pub fn max_expires_in(&self) -> u64 {
+        self.max_expires_in
+    }
+
+    /// 删除的文件在回收站中保留的时间，单位为秒，为0时不使用回收站，直接删除
+    pub fn trash_retention(&self) -> u64 {
+        self.trash_retention
+    }
+
+    /// S3后端的配置，仅当`storage`为`S3`时使用
+    pub fn s3(&self) -> Option<S3Config> {
+        self.s3.clone()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,415 @@
+use std::{fmt, io::Cursor};
+
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    metadata::Orientation,
+    DynamicImage, ImageDecoder, ImageEncoder, ImageError, ImageFormat, ImageReader, ImageResult,
+    Limits,
+};
+use serde_derive::{Deserialize, Serialize};
+
+/// 解码时允许的最大边长，防止构造的超大图片耗尽内存
+const MAX_DECODE_DIMENSION: u32 = 16384;
+
+/// 未指定`q`时使用的JPEG/AVIF质量
+const DEFAULT_QUALITY: u8 = 80;
+
+/// 上传时旋转图片后重新编码JPEG使用的质量，尽量减少画质损失
+const REENCODE_QUALITY: u8 = 92;
+
+/// AVIF编码速度，1最慢、压缩率最高，10最快
+const AVIF_SPEED: u8 = 10;
+
+/// # NegotiatedFormat
+///
+/// 客户端在`Accept`中声明支持时，可以代替原图返回的格式
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum NegotiatedFormat {
+    WebP,
+    Avif,
+}
+
+impl NegotiatedFormat {
+    pub fn image_format(&self) -> ImageFormat {
+        match self {
+            NegotiatedFormat::WebP => ImageFormat::WebP,
+            NegotiatedFormat::Avif => ImageFormat::Avif,
+        }
+    }
+
+    pub fn mime(&self) -> mime::Mime {
+        match self {
+            NegotiatedFormat::WebP => "image/webp".parse().unwrap(),
+            NegotiatedFormat::Avif => "image/avif".parse().unwrap(),
+        }
+    }
+}
+
+/// # Fit
+///
+/// 同时指定宽和高时，图片如何适应目标尺寸
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// 保持比例缩放到目标尺寸之内
+    #[default]
+    Contain,
+    /// 保持比例缩放到覆盖目标尺寸，再居中裁剪
+    Cover,
+    /// 不保持比例，拉伸到目标尺寸
+    Fill,
+}
+
+impl fmt::Display for Fit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// # Transform
+///
+/// 访问文件时通过查询参数指定的变换，例如`?w=320&h=240&fit=cover&q=80`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Transform {
+    #[serde(rename = "w")]
+    pub width: Option<u32>,
+    #[serde(rename = "h")]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub fit: Fit,
+    #[serde(rename = "q")]
+    pub quality: Option<u8>,
+}
+
+impl Transform {
+    /// 把图片缩放到`size`x`size`之内的缩略图变换
+    pub fn thumbnail(size: u32) -> Self {
+        Self {
+            width: Some(size),
+            height: Some(size),
+            ..Default::default()
+        }
+    }
+
+    /// 是否需要对原图做任何处理
+    pub fn is_identity(&self) -> bool {
+        self.width.is_none() && self.height.is_none()
+    }
+
+    /// 检查参数是否合法，返回错误描述
+    pub fn validate(&self, max_width: u32, max_height: u32) -> Result<(), String> {
+        match self.width {
+            Some(0) => return Err("w must be greater than 0.".to_string()),
+            Some(w) if w > max_width => {
+                return Err(format!("w must not be greater than {}.", max_width))
+            }
+            _ => {}
+        }
+        match self.height {
+            Some(0) => return Err("h must be greater than 0.".to_string()),
+            Some(h) if h > max_height => {
+                return Err(format!("h must not be greater than {}.", max_height))
+            }
+            _ => {}
+        }
+        match self.quality {
+            Some(q) if !(1..=100).contains(&q) => {
+                Err("q must be between 1 and 100.".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 用于区分不同变换结果的键，例如`w320-h240-cover-q80`
+    pub fn key(&self) -> String {
+        let width = self.width.map_or("auto".to_string(), |w| w.to_string());
+        let height = self.height.map_or("auto".to_string(), |h| h.to_string());
+        format!(
+            "w{}-h{}-{}-q{}",
+            width,
+            height,
+            self.fit,
+            self.quality.unwrap_or(DEFAULT_QUALITY)
+        )
+    }
+
+    /// 变换结果在缓存中的文件名，例如`w320-h240-cover-q80.jpg`
+    pub fn cache_key(&self, format: ImageFormat) -> String {
+        format!("{}.{}", self.key(), format.extensions_str()[0])
+    }
+
+    /// 还原为查询字符串，例如`w=320&h=240&fit=cover`
+    pub fn query(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(w) = self.width {
+            params.push(format!("w={}", w));
+        }
+        if let Some(h) = self.height {
+            params.push(format!("h={}", h));
+        }
+        if self.fit != Fit::default() {
+            params.push(format!("fit={}", self.fit));
+        }
+        if let Some(q) = self.quality {
+            params.push(format!("q={}", q));
+        }
+        params.join("&")
+    }
+}
+
+/// 根据文件名判断是否是支持处理的图片格式
+pub fn image_format(filename: &str) -> Option<ImageFormat> {
+    let extension = filename.rsplit_once('.')?.1;
+    match ImageFormat::from_extension(extension)? {
+        format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP | ImageFormat::Gif) => {
+            Some(format)
+        }
+        _ => None,
+    }
+}
+
+/// 原图是否可以转换为其他格式返回
+///
+/// GIF转换后会丢失动画，WebP本身已经足够小，因此只转换JPEG和PNG。
+pub fn is_negotiable(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Jpeg | ImageFormat::Png)
+}
+
+/// 限制了解码尺寸的读取器
+fn reader(data: &[u8], format: ImageFormat) -> ImageReader<Cursor<&[u8]>> {
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DECODE_DIMENSION);
+    limits.max_image_height = Some(MAX_DECODE_DIMENSION);
+    reader.limits(limits);
+    reader
+}
+
+/// 在限制尺寸的前提下解码图片，GIF只取第一帧
+pub fn decode(data: &[u8], format: ImageFormat) -> ImageResult<DynamicImage> {
+    reader(data, format).decode()
+}
+
+/// 把图片编码为指定格式，`quality`仅对JPEG和AVIF有效
+pub fn encode(image: &DynamicImage, format: ImageFormat, quality: u8) -> ImageResult<Vec<u8>> {
+    let mut out = Cursor::new(Vec::new());
+    match format {
+        // JPEG不支持透明通道
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality))?,
+        ImageFormat::Avif => image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+            &mut out,
+            AVIF_SPEED,
+            quality,
+        ))?,
+        _ => image.write_to(&mut out, format)?,
+    }
+    Ok(out.into_inner())
+}
+
+/// # auto_orient
+///
+/// 按EXIF中的方向旋转、翻转像素，把方向重置为正常后重新编码为原来的格式。
+///
+/// 原图的EXIF（方向已重置）和ICC色彩配置文件会写入新图片，XMP等其他元数据会丢失。
+/// 方向本来就正常时返回`None`，不做任何处理。
+pub fn auto_orient(data: &[u8], format: ImageFormat) -> ImageResult<Option<Vec<u8>>> {
+    let mut decoder = reader(data, format).into_decoder()?;
+    let orientation = decoder.orientation()?;
+    if orientation == Orientation::NoTransforms {
+        return Ok(None);
+    }
+    let exif = decoder.exif_metadata()?.map(|mut exif| {
+        let _ = Orientation::remove_from_exif_chunk(&mut exif);
+        exif
+    });
+    let icc_profile = decoder.icc_profile()?;
+
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let mut out = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => write_with_metadata(
+            &DynamicImage::ImageRgb8(image.to_rgb8()),
+            JpegEncoder::new_with_quality(&mut out, REENCODE_QUALITY),
+            exif,
+            icc_profile,
+        )?,
+        ImageFormat::Png => write_with_metadata(&image, PngEncoder::new(&mut out), exif, icc_profile)?,
+        ImageFormat::WebP => {
+            write_with_metadata(&image, WebPEncoder::new_lossless(&mut out), exif, icc_profile)?
+        }
+        // 其他格式没有方向信息，不会走到这里
+        _ => image.write_to(&mut out, format)?,
+    }
+    Ok(Some(out.into_inner()))
+}
+
+/// 带上EXIF和ICC配置文件编码图片
+fn write_with_metadata(
+    image: &DynamicImage,
+    mut encoder: impl ImageEncoder,
+    exif: Option<Vec<u8>>,
+    icc_profile: Option<Vec<u8>>,
+) -> ImageResult<()> {
+    if let Some(exif) = exif {
+        encoder.set_exif_metadata(exif).map_err(ImageError::Unsupported)?;
+    }
+    if let Some(icc_profile) = icc_profile {
+        encoder.set_icc_profile(icc_profile).map_err(ImageError::Unsupported)?;
+    }
+    image.write_with_encoder(encoder)
+}
+
+/// # resize
+///
+/// 按`transform`缩放图片，并编码为`output`格式。
+///
+/// 只指定宽或高时按比例缩放；两者都指定时按`fit`处理。图片不会被放大。
+/// 不指定宽高时只转换格式。
+pub fn resize(
+    data: &[u8],
+    input: ImageFormat,
+    output: ImageFormat,
+    transform: &Transform,
+) -> ImageResult<Vec<u8>> {
+    render(&decode(data, input)?, output, transform)
+}
+
+/// # thumbnails
+///
+/// 按`transforms`为已经解码的图片生成多个缩放后的版本
+pub fn thumbnails(
+    image: &DynamicImage,
+    format: ImageFormat,
+    transforms: &[Transform],
+) -> ImageResult<Vec<Vec<u8>>> {
+    transforms
+        .iter()
+        .map(|transform| render(image, format, transform))
+        .collect()
+}
+
+/// # dhash
+///
+/// 计算图片的差异哈希（dHash）：缩小为9x8的灰度图，逐行比较相邻像素的亮度。
+///
+/// 缩放、重新编码后的同一张图片，哈希之间的汉明距离很小。
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.thumbnail_exact(9, 8).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// # group_similar
+///
+/// 把感知哈希之间的汉明距离不超过`max_distance`的图片分为一组，只返回包含多张图片的组。
+///
+/// 相似关系会传递：A与B相似、B与C相似时，A、B、C在同一组中。
+pub fn group_similar(hashes: &[(String, u64)], max_distance: u32) -> Vec<Vec<String>> {
+    // 并查集，`parent[i]`是第i张图片所在组的上级
+    let mut parent: Vec<usize> = (0..hashes.len()).collect();
+    fn find(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for i in 0..hashes.len() {
+        for j in (i + 1)..hashes.len() {
+            if (hashes[i].1 ^ hashes[j].1).count_ones() <= max_distance {
+                let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                parent[b] = a;
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    let mut group_of_root = std::collections::HashMap::new();
+    for (i, (name, _)) in hashes.iter().enumerate() {
+        let root = find(&mut parent, i);
+        let index = *group_of_root.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[index].push(name.clone());
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+/// 不放大图片时`cover`实际使用的目标尺寸：保持`w`:`h`的比例，不超过原图的`width`x`height`
+fn cover_box(w: u32, h: u32, width: u32, height: u32) -> (u32, u32) {
+    let scale = (width as f64 / w as f64).min(height as f64 / h as f64).min(1.0);
+    let scaled = |target: u32, limit: u32| ((target as f64 * scale).round() as u32).clamp(1, limit.max(1));
+    (scaled(w, width), scaled(h, height))
+}
+
+/// 对已经解码的图片应用变换并编码
+fn render(image: &DynamicImage, format: ImageFormat, transform: &Transform) -> ImageResult<Vec<u8>> {
+    let quality = transform.quality.unwrap_or(DEFAULT_QUALITY);
+    if transform.is_identity() {
+        return encode(image, format, quality);
+    }
+
+    let (width, height) = (image.width(), image.height());
+    let target_width = transform.width.unwrap_or(u32::MAX).min(width);
+    let target_height = transform.height.unwrap_or(u32::MAX).min(height);
+
+    let resized = match (transform.width, transform.height, transform.fit) {
+        (Some(w), Some(h), Fit::Cover) => {
+            // 原图比目标小时整体等比缩小目标尺寸，分别截断宽高会改变裁剪的比例
+            let (w, h) = cover_box(w, h, width, height);
+            image.resize_to_fill(w, h, FilterType::Lanczos3)
+        }
+        (Some(_), Some(_), Fit::Fill) => {
+            image.resize_exact(target_width, target_height, FilterType::Lanczos3)
+        }
+        _ => image.resize(target_width, target_height, FilterType::Lanczos3),
+    };
+
+    encode(&resized, format, quality)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cover_box_keeps_aspect_ratio() {
+        assert_eq!(cover_box(320, 240, 1000, 1000), (320, 240));
+        assert_eq!(cover_box(400, 200, 100, 100), (100, 50));
+        assert_eq!(cover_box(200, 400, 300, 100), (50, 100));
+        assert_eq!(cover_box(1000, 1, 10, 10), (10, 1));
+    }
+
+    #[test]
+    fn cover_crops_small_images_to_requested_ratio() {
+        let image = DynamicImage::new_rgb8(100, 80);
+        let transform = Transform {
+            width: Some(400),
+            height: Some(200),
+            fit: Fit::Cover,
+            quality: None,
+        };
+        let rendered = render(&image, ImageFormat::Png, &transform).unwrap();
+        let rendered = image::load_from_memory(&rendered).unwrap();
+        assert_eq!((rendered.width(), rendered.height()), (100, 50));
+    }
+}
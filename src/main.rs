@@ -2,36 +2,66 @@ mod config;
 mod util;
 mod args;
 mod commands;
+mod storage;
+mod imaging;
+mod cache;
+mod metadata;
+mod sniff;
+mod policy;
+mod svg;
+mod store;
+mod stats;
+mod trash;
+mod name;
+mod auth;
 
 use log::{error, info, warn};
 use std::{
     fs::{self, File},
-    io::{Read, Write},
+    io::{self, Read},
     path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
-use actix_multipart::Multipart;
+use actix_multipart::{Field, Multipart};
 use actix_cors::Cors;
 use actix_web::{
     get,
-    http::header::ContentType,
-    post,
+    http::{
+        header::{self, ContentType, EntityTag, Header, HttpDate},
+        Method, StatusCode,
+    },
+    post, route,
     web::{self, Bytes},
-    App, HttpResponse, HttpServer, Responder,
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use futures_util::stream::StreamExt;
-use log4rs;
-use new_mime_guess;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use clap::Parser;
+use image::ImageFormat;
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
 
 use crate::config::{
     Config,
+    MismatchAction,
     UploadMode
 };
 use crate::util::*;
+use crate::name::StoredName;
+use crate::auth::{ApiToken, Scope, Tokens};
 use crate::args::*;
+use crate::storage::{LocalStorage, Storage};
+use crate::imaging::{NegotiatedFormat, Transform};
+use crate::cache::RenditionCache;
+use crate::metadata::StripOptions;
+use crate::policy::ActiveContentPolicy;
+use crate::store::{FileRecord, MetaStore};
+use crate::stats::StorageStats;
+use crate::trash::Trash;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -40,7 +70,37 @@ async fn main() -> std::io::Result<()> {
     // 处理命令行参数
     match &cli.command {
         Some(Commands::Clear) => {
-            commands::clear_storage();
+            commands::clear_storage().await;
+            return Ok(());
+        },
+        Some(Commands::Duplicates { distance }) => {
+            commands::list_duplicates(*distance).await;
+            return Ok(());
+        },
+        Some(Commands::List { offset, limit }) => {
+            commands::list_files(*offset, *limit).await;
+            return Ok(());
+        },
+        Some(Commands::Migrate) => {
+            commands::migrate_layout().await;
+            return Ok(());
+        },
+        Some(Commands::Trash { command }) => {
+            match command {
+                TrashCommands::List => commands::list_trash().await,
+                TrashCommands::Restore { name } => commands::restore_from_trash(name).await,
+                TrashCommands::Empty => commands::empty_trash().await,
+            }
+            return Ok(());
+        },
+        Some(Commands::Token { command }) => {
+            match command {
+                TokenCommands::Add { name, scopes, expires_in, max_file_size } => {
+                    commands::add_token(name, scopes, *expires_in, *max_file_size).await
+                }
+                TokenCommands::List => commands::list_tokens().await,
+                TokenCommands::Revoke { name } => commands::revoke_token(name).await,
+            }
             return Ok(());
         },
         None => {}
@@ -70,27 +130,95 @@ async fn main() -> std::io::Result<()> {
     info!("Max file size: {}", format_file_size(max_file_size));
     let use_token = config.use_token();
     info!("Use token: {}", use_token);
-    let hashed_token = config.hashed_token();
-    if use_token {
-        info!("Hashed token: {}", hashed_token);
-    }
     let upload_mode = config.upload_mode();
     info!("Upload mode: {:?}", upload_mode);
     let upload_whitelist = config.upload_whitelist();
     info!("Upload whitelist: {:?}", upload_whitelist);
     let upload_blacklist = config.upload_blacklist();
     info!("Upload blacklist: {:?}", upload_blacklist);
+    let extension_mismatch = config.extension_mismatch();
+    info!("Extension mismatch: {:?}", extension_mismatch);
+    let active_content = config.active_content();
+    info!("Active content policy: {:?}", active_content);
+    let cache_control = config.cache_control().to_string();
+    info!("Cache-Control: {}", &cache_control);
+    let resize_max_width = config.resize_max_width();
+    let resize_max_height = config.resize_max_height();
+    info!("Max resize dimensions: {}x{}", resize_max_width, resize_max_height);
+    let rendition_cache_size = config.rendition_cache_size();
+    info!("Rendition cache size: {}", format_file_size(rendition_cache_size as usize));
+    let mut thumbnails = config.thumbnails();
+    thumbnails.retain(|&size| {
+        let allowed = size > 0 && size <= resize_max_width && size <= resize_max_height;
+        if !allowed {
+            warn!("Thumbnail size {} is out of the resize limits, ignored.", size);
+        }
+        allowed
+    });
+    info!("Thumbnail sizes: {:?}", thumbnails);
+    let negotiate_formats = config.negotiate_formats();
+    info!("Negotiated formats: {:?}", negotiate_formats);
+    let auto_orient = config.auto_orient();
+    info!("Auto orient: {}", auto_orient);
+    let strip_metadata = config.strip_metadata().then(|| StripOptions {
+        keep_orientation: config.keep_orientation(),
+        keep_color_profile: config.keep_color_profile(),
+    });
+    info!("Strip metadata: {:?}", strip_metadata);
+    let dedup = config.dedup();
+    info!("Deduplication: {}", dedup);
+    let default_expires_in = config.default_expires_in();
+    let max_expires_in = config.max_expires_in();
+    info!("Default expiry: {}s, max expiry: {}s", default_expires_in, max_expires_in);
 
-    let file_storage_path = format!("{}/file", www_root);
-    if !Path::new(&file_storage_path).exists() {
-        warn!("File storage path {} not exists, making it.", &file_storage_path);
-        fs::create_dir(&file_storage_path).unwrap();
-    }
-    let total_size = calculate_total_size(&file_storage_path);
+    info!("Storage backend: {:?}", config.storage());
+    let storage = storage::from_config(&config)?;
+    let (total_count, total_size) = scan_storage(storage.as_ref()).await?;
     let total_size_str = format_file_size(total_size as usize);
     info!("File storage total size: {}", total_size_str);
-    let total_count = get_file_count(&file_storage_path);
     info!("File count: {}", total_count);
+    let stats = Arc::new(StorageStats::new(total_count as u64, total_size));
+    let stats_reconcile_interval = config.stats_reconcile_interval();
+    info!("Statistics reconcile interval: {}s", stats_reconcile_interval);
+    if stats_reconcile_interval > 0 {
+        actix_web::rt::spawn(reconcile_stats(
+            storage.clone(),
+            stats.clone(),
+            Duration::from_secs(stats_reconcile_interval),
+        ));
+    }
+
+    // 上传中的文件先写到这里，启动时清掉上次异常退出留下的残余
+    let temp_dir = format!("{}/tmp", www_root);
+    if Path::new(&temp_dir).exists() {
+        fs::remove_dir_all(&temp_dir)?;
+    }
+    fs::create_dir_all(&temp_dir)?;
+
+    let store = MetaStore::open(format!("{}/meta.db", www_root))?;
+    let tokens = Tokens::new(config.tokens(), store.clone());
+    info!("Tokens in config: {:?}", tokens.configured_names());
+    let trash_retention = config.trash_retention();
+    info!("Trash retention: {}s", trash_retention);
+    let trash = match trash_retention {
+        0 => None,
+        retention => Some(Trash::new(
+            storage.clone(),
+            storage::trash_from_config(&config)?,
+            store.clone(),
+            retention,
+        )),
+    };
+    let backfilled = store.backfill(storage.as_ref()).await?;
+    if backfilled > 0 {
+        info!("Added upload records for {} existing file(s).", backfilled);
+    }
+    let static_files = Arc::new(LocalStorage::new(&www_root)?);
+    let rendition_cache = Arc::new(RenditionCache::new(
+        format!("{}/cache", www_root),
+        rendition_cache_size,
+    )
+    .await?);
 
     let app_state = AppState {
         www_root,
@@ -100,14 +228,35 @@ async fn main() -> std::io::Result<()> {
         proxy,
         max_file_size,
         use_token,
-        hashed_token,
+        tokens,
         upload_mode,
         upload_whitelist,
         upload_blacklist,
+        extension_mismatch,
+        active_content,
+        storage,
+        static_files,
+        temp_dir,
+        cache_control,
+        resize_max_width,
+        resize_max_height,
+        rendition_cache,
+        thumbnails,
+        negotiate_formats,
+        auto_orient,
+        strip_metadata,
+        dedup,
+        store,
+        stats,
+        default_expires_in,
+        max_expires_in,
+        trash,
     };
+    actix_web::rt::spawn(reap_expired(app_state.clone()));
 
     let server = match HttpServer::new(move || {
-        let cors = Cors::default().allow_any_origin();
+        // 跨域的浏览器脚本也要能读到纯文本响应中的删除URL
+        let cors = Cors::default().allow_any_origin().expose_headers(["X-Delete-Url"]);
         App::new()
             .wrap(cors)
             .app_data(web::Data::new(app_state.clone()))
@@ -115,6 +264,12 @@ async fn main() -> std::io::Result<()> {
             .service(get_file)
             .service(upload_file)
             .service(delete_file)
+            .service(delete_file_by_url)
+            .service(list_duplicates)
+            .service(file_info)
+            .service(storage_stats)
+            .service(list_trash)
+            .service(restore_file)
     })
     .bind((listen_ip, port))
     {
@@ -131,7 +286,7 @@ async fn main() -> std::io::Result<()> {
     server.run().await
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 struct AppState {
     www_root: String,
     ssl: bool,
@@ -140,10 +295,147 @@ struct AppState {
     proxy: bool,
     max_file_size: usize,
     use_token: bool,
-    hashed_token: String,
+    /// 配置文件和元数据库中的所有token
+    tokens: Tokens,
     upload_mode: UploadMode,
     upload_whitelist: Vec<String>,
     upload_blacklist: Vec<String>,
+    extension_mismatch: MismatchAction,
+    active_content: ActiveContentPolicy,
+    storage: Arc<dyn Storage>,
+    /// 服务自带的静态文件（favicon等），直接存放在www_root下
+    static_files: Arc<LocalStorage>,
+    temp_dir: String,
+    cache_control: String,
+    resize_max_width: u32,
+    resize_max_height: u32,
+    rendition_cache: Arc<RenditionCache>,
+    thumbnails: Vec<u32>,
+    negotiate_formats: Vec<NegotiatedFormat>,
+    /// 上传时是否按EXIF中的方向旋转图片
+    auto_orient: bool,
+    /// 上传时去除元数据的选项，为`None`时原样保存
+    strip_metadata: Option<StripOptions>,
+    /// 是否对上传的文件去重
+    dedup: bool,
+    store: MetaStore,
+    /// 文件数和总大小的计数
+    stats: Arc<StorageStats>,
+    /// 上传时没有指定有效期时使用的有效期（秒），为0时永不过期
+    default_expires_in: u64,
+    /// 最长有效期（秒），为0时不限制
+    max_expires_in: u64,
+    /// 回收站，不使用回收站时为`None`
+    trash: Option<Trash>,
+}
+
+/// 检查并删除过期文件的间隔
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// # reap_expired
+///
+/// 每隔`REAP_INTERVAL`删除一次已经过期的文件，并清理回收站中超过保留时间的文件
+async fn reap_expired(data: AppState) {
+    let mut ticker = tokio::time::interval(REAP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Some(trash) = &data.trash {
+            match trash.purge_expired().await {
+                Ok(purged) => {
+                    for name in purged {
+                        info!("File {} purged from trash.", &name);
+                    }
+                }
+                Err(e) => warn!("Error purging trash: {}", e),
+            }
+        }
+        let expired = match data.store.expired(get_time()).await {
+            Ok(expired) => expired,
+            Err(e) => {
+                warn!("Error querying expired files: {}", e);
+                continue;
+            }
+        };
+        for name in expired {
+            match remove_file(&data, &name).await {
+                Ok(_) => info!("Expired file {} deleted.", &name),
+                // 文件已经不在了，只需清理记录
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    if let Err(e) = data.store.forget(&name).await {
+                        warn!("Error removing metadata of {}: {}", &name, e);
+                    }
+                }
+                Err(e) => {
+                    warn!("Error deleting expired file {}: {}", &name, e);
+                    continue;
+                }
+            }
+            if let Err(e) = data.store.mark_gone(&name).await {
+                warn!("Error marking {} as gone: {}", &name, e);
+            }
+        }
+    }
+}
+
+/// # move_to_trash
+///
+/// 把文件移入回收站，并清理它的缓存和计数
+async fn move_to_trash(data: &AppState, trash: &Trash, name: &str) -> io::Result<()> {
+    let size = trash.put(name).await?;
+    data.stats.remove(size);
+    if let Err(e) = data.rendition_cache.remove_source(name).await {
+        warn!("Error removing cached renditions of {}: {}", name, e);
+    }
+    Ok(())
+}
+
+/// # remove_file
+///
+/// 从存储中删除文件，并清理它的缓存、元数据和计数
+///
+/// 不检查去重的引用次数。会过期或限制访问次数的上传不参与去重，所以过期和访问次数用完时可以直接调用；
+/// 删除请求需要先用`release`确认已经没有其他引用。
+async fn remove_file(data: &AppState, name: &str) -> io::Result<()> {
+    // 删除之前取得文件大小，用于更新计数；取不到时由定期校正修复
+    let size = data.storage.stat(name).await.map_or(0, |stat| stat.size);
+    data.storage.delete(name).await?;
+    data.stats.remove(size);
+    if let Err(e) = data.rendition_cache.remove_source(name).await {
+        warn!("Error removing cached renditions of {}: {}", name, e);
+    }
+    if let Err(e) = data.store.forget(name).await {
+        warn!("Error removing metadata of {}: {}", name, e);
+    }
+    Ok(())
+}
+
+/// # reconcile_stats
+///
+/// 每隔`interval`扫描一次存储，用实际的文件数和总大小校正计数
+async fn reconcile_stats(storage: Arc<dyn Storage>, stats: Arc<StorageStats>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // 第一次立即触发，而启动时刚刚扫描过
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        match scan_storage(storage.as_ref()).await {
+            Ok((count, size)) => {
+                let count = count as u64;
+                let before = stats.snapshot();
+                if before.count != count || before.total_size != size {
+                    info!(
+                        "Statistics reconciled: {} file(s), {} -> {} file(s), {}.",
+                        before.count,
+                        format_file_size(before.total_size as usize),
+                        count,
+                        format_file_size(size as usize)
+                    );
+                }
+                stats.set(count, size);
+            }
+            Err(e) => warn!("Error scanning storage for statistics: {}", e),
+        }
+    }
 }
 
 #[get("/")]
@@ -173,20 +465,16 @@ async fn index(data: web::Data<AppState>) -> impl Responder {
     };
 
     let index_path = format!("{}/index.html", www_root);
-    let file_storage_path = format!("{}/file", www_root);
-    let total_size = calculate_total_size(&file_storage_path);
-    let total_size_str = format_file_size(total_size as usize);
-    let total_count = get_file_count(&file_storage_path);
-    let mut index_file = File::open(&index_path)
-        .map_err(|e| {
-            eprintln!("Couldn't open index.html: {}", e);
-            HttpResponse::InternalServerError().finish()
-        })
-        .unwrap();
-    let mut index_content = String::new();
-    index_file
-        .read_to_string(&mut index_content)
-        .expect("Couldn't read index.html!");
+    let stats = data.stats.snapshot();
+    let total_size_str = format_file_size(stats.total_size as usize);
+    let total_count = stats.count;
+    let mut index_content = match tokio::fs::read_to_string(&index_path).await {
+        Ok(content) => content,
+        Err(e) => {
+            error!("Couldn't read index.html: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
     let replacements = [
         ("UPLOAD", &request_url),
         ("TOTAL_SIZE", &total_size_str),
@@ -208,134 +496,588 @@ async fn index(data: web::Data<AppState>) -> impl Responder {
 }
 
 // 存在于白名单中的文件将被认为存放在www_root下，而不是www_root/file下
-const FILE_WHITELIST: [&'static str; 2] = ["favicon.ico", "style.css", "CircularBody.woff"];
+const FILE_WHITELIST: [&str; 3] = ["favicon.ico", "style.css", "CircularBody.woff"];
 
-#[get("/{filename}")]
-async fn get_file(data: web::Data<AppState>, filename: web::Path<String>) -> impl Responder {
+#[route("/{filename}", method = "GET", method = "HEAD")]
+async fn get_file(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    filename: web::Path<String>,
+    transform: web::Query<Transform>,
+) -> impl Responder {
     let www_root = &data.www_root;
-    let file_path = if FILE_WHITELIST.contains(&filename.as_str()) {
-        format!("{}/{}", www_root, filename)
+    // 服务自带的静态文件直接从www_root读取，其余的交给存储后端
+    let is_static = FILE_WHITELIST.contains(&filename.as_str());
+    let storage: &dyn Storage = if is_static {
+        data.static_files.as_ref()
     } else {
-        format!("{}/file/{}", www_root, filename)
+        data.storage.as_ref()
+    };
+    // 除了静态文件，只接受服务器生成的文件名
+    if !is_static {
+        if let Err(msg) = filename.parse::<StoredName>() {
+            warn!("Rejected request for {}: {}", &filename, &msg);
+            return HttpResponse::BadRequest().body(msg);
+        }
+    }
+
+    // 过期的文件在被定期删除之前同样不可访问
+    let record = if is_static {
+        None
+    } else {
+        match data.store.file(&filename).await {
+            Ok(record) => record,
+            Err(e) => {
+                error!("Error reading upload record of {}: {}", &filename, e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    };
+    if record
+        .as_ref()
+        .and_then(|record| record.expires_at)
+        .is_some_and(|expires_at| expires_at <= get_time())
+    {
+        info!("File {} has expired.", &filename);
+        return HttpResponse::Gone().body(format!("{} has expired", filename));
+    }
+    // 限制访问次数的文件不能被缓存，也不能交给存储后端直接返回
+    let limited = record.is_some_and(|record| record.views_left.is_some());
+
+    // 查询参数中指定了尺寸时返回缩放后的图片，静态文件不做处理
+    let (transform, source_format) = if is_static {
+        (Transform::default(), None)
+    } else {
+        (transform.into_inner(), imaging::image_format(&filename))
+    };
+    if !transform.is_identity() {
+        if let Err(msg) = transform.validate(data.resize_max_width, data.resize_max_height) {
+            warn!("Invalid transform for {}: {}", &filename, &msg);
+            return HttpResponse::BadRequest().body(msg);
+        }
+        if source_format.is_none() {
+            warn!("File {} can't be resized.", &filename);
+            return HttpResponse::BadRequest()
+                .body(format!("File {} is not an image that can be resized.", &filename));
+        }
+    }
+
+    // 客户端支持时，把JPEG/PNG转换为更小的格式返回
+    let negotiable = !data.negotiate_formats.is_empty()
+        && source_format.is_some_and(imaging::is_negotiable);
+    let negotiated = if negotiable {
+        preferred_format(&req, &data.negotiate_formats)
+    } else {
+        None
+    };
+    let needs_processing = !transform.is_identity() || negotiated.is_some();
+
+    // 可能包含脚本的文件按策略附加限制，静态文件是服务自己的页面，不做限制
+    let mut content_type = new_mime_guess::from_path(filename.as_str()).first_or_octet_stream();
+    let (attachment, csp) = if is_static {
+        (false, None)
+    } else {
+        (
+            data.active_content.attachment(&content_type),
+            data.active_content.content_security_policy(&content_type),
+        )
     };
-    let mut file = match File::open(&file_path) {
-        Ok(f) => f,
-        Err(_) => {
-            // 文件不存在，返回404错误，使用404.html作为响应
-            let not_found_path = format!("{}/404.html", www_root);
-            let not_found_content = match File::open(&not_found_path) {
-                Ok(mut file) => {
-                    let mut content = Vec::new();
-                    file.read_to_end(&mut content).unwrap();
-                    content
-                }
-                Err(_) => "<h1>404 Not Found</h1>".as_bytes().to_vec(),
-            };
 
+    match storage.presigned_url(&filename).await {
+        // 需要处理图片、附加响应头或计数时只能由本服务完成，不能重定向
+        Ok(Some(_)) if needs_processing || csp.is_some() || limited => {}
+        Ok(Some(url)) => {
+            info!("Request for {} redirected to storage backend.", &filename);
+            return HttpResponse::Found()
+                .insert_header((header::LOCATION, url))
+                .finish();
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Error signing URL for {}: {}", &filename, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let stat = match storage.stat(&filename).await {
+        Ok(s) => s,
+        // 过期或访问次数用完而被删除的文件返回410
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound
+                && !is_static
+                && data.store.is_gone(&filename).await.unwrap_or(false) =>
+        {
+            info!("File {} is gone.", &filename);
+            return HttpResponse::Gone().body(format!("{} is no longer available", filename));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
             warn!("File {} not found when trying to access it.", &filename);
+            return not_found(www_root);
+        }
+        // 解析后的路径不在存储目录之下
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            warn!("Rejected request for {}: {}", &filename, e);
+            return HttpResponse::BadRequest().body(e.to_string());
+        }
+        Err(e) => {
+            error!("Error reading file {}: {}", &filename, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let file_size = stat.size;
 
-            return HttpResponse::NotFound()
-                .content_type("text/html; charset=utf-8")
-                .body(Bytes::from(not_found_content));
+    // 处理后的图片由文件名和变换决定，在处理之前就能得到ETag，条件请求命中时不必解码和编码图片
+    let etag_suffix = if transform.is_identity() {
+        String::new()
+    } else {
+        format!("-{}", transform.key())
+    };
+
+    // 存储中的文件名由内容哈希得到，内容不会再变化，直接用文件名作为强ETag；
+    // 静态文件可能随版本更新，使用修改时间和大小
+    let modified_secs = stat.modified.map(truncate_to_secs);
+    let last_modified = modified_secs.map(HttpDate::from);
+    let etag = if is_static {
+        let mtime = modified_secs
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        EntityTag::new_strong(format!("{:x}-{:x}", mtime, file_size))
+    } else {
+        let stem = filename.split('.').next().unwrap_or_default();
+        EntityTag::new_strong(format!("{}{}", stem, etag_suffix))
+    };
+    let cache_control = if is_static {
+        "no-cache"
+    } else if limited {
+        "no-store"
+    } else {
+        data.cache_control.as_str()
+    };
+
+    // 协商的格式只在转换结果更小时才返回，处理之前不知道最终返回哪个版本，
+    // 两个版本的ETag都是候选，只有真正返回了转换后的版本时ETag才带上格式
+    let mut candidates = vec![etag];
+    if let Some(target) = negotiated {
+        let tag = format!("{}-{}", candidates[0].tag(), target.image_format().extensions_str()[0]);
+        candidates.push(EntityTag::new_strong(tag));
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header((header::CACHE_CONTROL, cache_control));
+    if let Some(modified) = last_modified {
+        response.insert_header(header::LastModified(modified));
+    }
+    // 返回的格式取决于Accept，需要告诉缓存按Accept区分
+    if negotiable {
+        response.insert_header((header::VARY, "Accept"));
+    }
+    if !is_static {
+        response.insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"));
+    }
+    if let Some(csp) = csp {
+        response.insert_header((header::CONTENT_SECURITY_POLICY, csp));
+    }
+    if attachment {
+        response.insert_header((header::CONTENT_DISPOSITION, "attachment"));
+    }
+
+    if !limited {
+        if let Some(etag) = not_modified(&req, &candidates, last_modified) {
+            info!("Request for {} not modified.", &filename);
+            return response
+                .insert_header(header::ETag(etag))
+                .status(StatusCode::NOT_MODIFIED)
+                .finish();
+        }
+    }
+
+    // 处理后的图片在内存中生成，优先使用协商的格式，但只在它比原本要返回的内容更小时才使用
+    let mut rendered = None;
+    let mut converted_served = false;
+    if let (true, Some(format)) = (needs_processing, source_format) {
+        let baseline = if transform.is_identity() {
+            None
+        } else {
+            match rendition(&data, storage, &filename, format, format, &transform).await {
+                Ok(content) => Some(content),
+                Err(response) => return response,
+            }
+        };
+        if let Some(target) = negotiated {
+            let converted =
+                match rendition(&data, storage, &filename, format, target.image_format(), &transform).await {
+                    Ok(content) => content,
+                    Err(response) => return response,
+                };
+            let baseline_size = baseline.as_ref().map_or(file_size, |b| b.len() as u64);
+            if (converted.len() as u64) < baseline_size {
+                info!("Request for {} negotiated to {:?}.", &filename, target);
+                content_type = target.mime();
+                rendered = Some(converted);
+                converted_served = true;
+            }
+        }
+        if rendered.is_none() {
+            rendered = baseline;
+        }
+    }
+
+    // 有多个候选时，`*`和If-Modified-Since要等知道返回哪个版本之后才能回答
+    let deferred = candidates.len() > 1;
+    let etag = candidates.swap_remove(usize::from(converted_served));
+    if deferred && !limited {
+        if let Some(etag) = not_modified(&req, std::slice::from_ref(&etag), last_modified) {
+            info!("Request for {} not modified.", &filename);
+            return response
+                .insert_header(header::ETag(etag))
+                .status(StatusCode::NOT_MODIFIED)
+                .finish();
+        }
+    }
+    response
+        .insert_header(header::ETag(etag.clone()))
+        .insert_header(ContentType(content_type.clone()));
+
+    // 限制访问次数的文件，每次GET消耗一次，最后一次返回之后立即删除
+    if limited && req.method() != Method::HEAD {
+        match data.store.take_view(&filename).await {
+            Ok(Some(0)) => {
+                // 原文件先复制到临时文件中再删除，之后从临时文件返回，不必把整个文件读入内存
+                let copy = match rendered {
+                    Some(_) => None,
+                    None => match crate::storage::download(storage, &filename, Path::new(&data.temp_dir)).await {
+                        Ok(temp_file) => Some(temp_file),
+                        Err(e) => {
+                            error!("Error reading file {}: {}", &filename, e);
+                            return HttpResponse::InternalServerError().finish();
+                        }
+                    },
+                };
+                match remove_file(&data, &filename).await {
+                    Ok(_) => info!("Last view of {} used, file deleted.", &filename),
+                    Err(e) => error!("Error deleting file {} after its last view: {}", &filename, e),
+                }
+                if let Err(e) = data.store.mark_gone(&filename).await {
+                    warn!("Error marking {} as gone: {}", &filename, e);
+                }
+                let Some(temp_file) = copy else {
+                    return response.body(rendered.unwrap_or_default());
+                };
+                let file = match temp_file.reopen() {
+                    Ok(file) => tokio::fs::File::from_std(file),
+                    Err(e) => {
+                        error!("Error reading temp file: {}", e);
+                        return HttpResponse::InternalServerError().finish();
+                    }
+                };
+                // 响应发送完之前保留临时文件
+                let temp_path = temp_file.into_temp_path();
+                let file_stream = ReaderStream::new(file).map(move |chunk| {
+                    let _ = &temp_path;
+                    chunk
+                });
+                return response.no_chunking(file_size).streaming(file_stream);
+            }
+            Ok(Some(remaining)) => info!("{} view(s) of {} left.", remaining, &filename),
+            Ok(None) => {
+                info!("No views of {} left.", &filename);
+                return HttpResponse::Gone().body(format!("{} is no longer available", filename));
+            }
+            Err(e) => {
+                error!("Error counting views of {}: {}", &filename, e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+
+    // 处理后的图片不支持Range
+    if let Some(content) = rendered {
+        info!("Request for {} OK. MIME is {}.", &filename, &content_type);
+        return response.body(content);
+    }
+
+    // 只支持单个区间的Range请求，多个区间时按普通请求返回整个文件；
+    // 限制访问次数的文件每次请求都会消耗次数，总是返回整个文件
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .filter(|_| !limited)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<header::Range>().ok())
+        .and_then(|r| match r {
+            header::Range::Bytes(specs) if specs.len() == 1 => Some(specs[0].clone()),
+            _ => None,
+        })
+        // If-Range与当前文件不匹配时忽略Range，返回整个文件
+        .filter(|_| {
+            if !req.headers().contains_key(header::IF_RANGE) {
+                return true;
+            }
+            match header::IfRange::parse(&req) {
+                Ok(header::IfRange::EntityTag(tag)) => tag.strong_eq(&etag),
+                Ok(header::IfRange::Date(date)) => last_modified.is_some_and(|m| m <= date),
+                Err(_) => false,
+            }
+        });
+
+    if !limited {
+        response.insert_header((header::ACCEPT_RANGES, "bytes"));
+    }
+    let (start, end) = match range {
+        Some(spec) => match spec.to_satisfiable_range(file_size) {
+            Some((start, end)) => {
+                response.status(StatusCode::PARTIAL_CONTENT).insert_header((
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, file_size),
+                ));
+                (start, end)
+            }
+            None => {
+                warn!("Unsatisfiable range requested for {}.", &filename);
+                return HttpResponse::RangeNotSatisfiable()
+                    .insert_header((header::CONTENT_RANGE, format!("bytes */{}", file_size)))
+                    .finish();
+            }
+        },
+        None => (0, file_size.saturating_sub(1)),
+    };
+    let length = if file_size == 0 { 0 } else { end - start + 1 };
+
+    // HEAD请求只需要响应头，不必打开文件
+    if req.method() == Method::HEAD {
+        return response
+            .no_chunking(length)
+            .streaming(futures_util::stream::empty::<io::Result<Bytes>>());
+    }
+
+    let file_stream = if length == file_size {
+        storage.stream(&filename).await
+    } else {
+        storage.stream_range(&filename, start, end).await
+    };
+    let file_stream = match file_stream {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Error reading file {}: {}", &filename, e);
+            return HttpResponse::InternalServerError().finish();
         }
     };
-    let mut content = Vec::new();
 
-    // 以字节数组的形式读取文件内容
-    let file_content = web::block(move || {
-        file.read_to_end(&mut content).unwrap();
-        content
+    info!("Request for {} OK. MIME is {}.", &filename, &content_type);
+
+    response.no_chunking(length).streaming(file_stream)
+}
+
+/// 从`Accept`头中选出客户端明确支持的第一个候选格式
+///
+/// `*/*`和`image/*`不算数，否则不认识新格式的客户端也会收到它们。
+fn preferred_format(req: &HttpRequest, candidates: &[NegotiatedFormat]) -> Option<NegotiatedFormat> {
+    let accept = header::Accept::parse(req).ok()?;
+    candidates.iter().copied().find(|candidate| {
+        let mime = candidate.mime();
+        accept
+            .iter()
+            .any(|item| item.item == mime && item.quality > header::Quality::ZERO)
     })
-    .await
-    .map_err(|e| {
-        eprintln!("Error reading file: {:?}", e);
+}
+
+/// 读取缓存，或者生成`filename`按`transform`处理并编码为`output`格式的图片
+///
+/// 失败时返回应当直接交给客户端的响应。
+async fn rendition(
+    data: &AppState,
+    storage: &dyn Storage,
+    filename: &str,
+    input: ImageFormat,
+    output: ImageFormat,
+    transform: &Transform,
+) -> Result<Bytes, HttpResponse> {
+    let cache_key = transform.cache_key(output);
+    if let Some(content) = data.rendition_cache.get(filename, &cache_key).await {
+        info!("Rendition {} of {} served from cache.", &cache_key, filename);
+        return Ok(content);
+    }
+    let original = storage.get(filename).await.map_err(|e| {
+        error!("Error reading file {}: {}", filename, e);
         HttpResponse::InternalServerError().finish()
-    })
-    .unwrap();
+    })?;
+    let transform = *transform;
+    let rendered = web::block(move || imaging::resize(&original, input, output, &transform)).await;
+    match rendered {
+        Ok(Ok(content)) => {
+            info!("Rendition {} of {} generated.", &cache_key, filename);
+            let content = Bytes::from(content);
+            if let Err(e) = data.rendition_cache.put(filename, &cache_key, content.clone()).await {
+                warn!("Error caching rendition of {}: {}", filename, e);
+            }
+            Ok(content)
+        }
+        Ok(Err(e)) => {
+            warn!("Error processing file {}: {}", filename, e);
+            Err(HttpResponse::UnprocessableEntity()
+                .body(format!("File {} can't be decoded as an image.", filename)))
+        }
+        Err(e) => {
+            error!("Error processing file {}: {}", filename, e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
 
-    let guess = new_mime_guess::from_path(filename.as_str())
-        .first()
-        .unwrap();
+/// # not_modified
+///
+/// 检查条件请求，客户端缓存的版本仍然有效时返回这个版本的ETag。
+///
+/// If-None-Match存在时忽略If-Modified-Since。客户端缓存了`candidates`中的任何一个都算有效；
+/// 有多个候选时无法确定`*`和If-Modified-Since对应哪个版本，返回`None`。
+///
+/// ## 参数
+/// - `candidates`: 可能返回的各个版本的ETag
+/// - `last_modified`: 文件的修改时间
+fn not_modified(
+    req: &HttpRequest,
+    candidates: &[EntityTag],
+    last_modified: Option<HttpDate>,
+) -> Option<EntityTag> {
+    let headers = req.headers();
+    let only = match candidates {
+        [only] => Some(only),
+        _ => None,
+    };
+    if headers.contains_key(header::IF_NONE_MATCH) {
+        match header::IfNoneMatch::parse(req) {
+            Ok(header::IfNoneMatch::Any) => only.cloned(),
+            Ok(header::IfNoneMatch::Items(tags)) => candidates
+                .iter()
+                .find(|candidate| tags.iter().any(|t| t.weak_eq(candidate)))
+                .cloned(),
+            Err(_) => None,
+        }
+    } else if headers.contains_key(header::IF_MODIFIED_SINCE) {
+        match (header::IfModifiedSince::parse(req), last_modified) {
+            (Ok(header::IfModifiedSince(since)), Some(modified)) if modified <= since => only.cloned(),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
 
-    info!("Request for {} OK. MIME is {}.", &filename, &guess);
+/// 把时间截断到秒，HTTP日期只精确到秒
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
 
-    HttpResponse::Ok()
-        .insert_header(ContentType(guess))
-        .body(Bytes::from(file_content))
+/// 返回404错误，使用404.html作为响应
+fn not_found(www_root: &str) -> HttpResponse {
+    let not_found_path = format!("{}/404.html", www_root);
+    let not_found_content = match File::open(&not_found_path) {
+        Ok(mut file) => {
+            let mut content = Vec::new();
+            file.read_to_end(&mut content).unwrap();
+            content
+        }
+        Err(_) => "<h1>404 Not Found</h1>".as_bytes().to_vec(),
+    };
+
+    HttpResponse::NotFound()
+        .content_type("text/html; charset=utf-8")
+        .body(Bytes::from(not_found_content))
 }
 
 #[post("/upload")]
-async fn upload_file(data: web::Data<AppState>, mut payload: Multipart) -> impl Responder {
-    let www_root = &data.www_root;
+async fn upload_file(req: HttpRequest, data: web::Data<AppState>, mut payload: Multipart) -> impl Responder {
     let ssl = data.ssl;
     let host = &data.host;
     let port = data.port;
     let proxy = data.proxy;
-    let max_file_size = data.max_file_size;
-    let max_file_size_str = format_file_size(max_file_size);
-    let use_token = data.use_token;
-    let hashed_token = &data.hashed_token;
+    let mut max_file_size = data.max_file_size;
     let upload_mode = &data.upload_mode;
     let upload_whitelist = &data.upload_whitelist;
     let upload_blacklist = &data.upload_blacklist;
 
     // 生成一个唯一的文件名（基于文件内容的哈希值）
     let mut hasher = Sha256::new();
-    let mut file_content = Vec::new();
-
-    // 先接收token
-    if use_token {
-        match payload.next().await.unwrap() {
-            Ok(mut field) => {
-                let mut token_chunk = Vec::new();
-                while let Some(chunk) = field.next().await {
-                    token_chunk.extend_from_slice(&chunk.unwrap());
-                }
-                let token = String::from_utf8(token_chunk).unwrap();
-                if get_str_sha256(&token) != *hashed_token {
-                    return HttpResponse::BadRequest().body("Incorrect token!");
+
+    // token可以放在请求头中，也可以是表单中任意位置的token字段
+    let mut token = request_token(&req);
+    let mut api_token = None;
+    let mut authorized = false;
+
+    let mut file_extension;
+    let original_name;
+    let mime_type;
+    // 文件先写入临时文件，通过所有检查后再放入存储；中途返回时临时文件会被自动删除
+    let temp_file = match NamedTempFile::new_in(&data.temp_dir) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Error creating temp file: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // 然后接收文件，文件之前可以带有选项字段
+    let mut options = UploadOptions::default();
+    let file_field = loop {
+        match payload.next().await {
+            Some(Ok(mut field)) if field.name() == "token" => match read_token(&mut field).await {
+                Some(value) => token = Some(value),
+                None => return unauthorized("Invalid token field."),
+            },
+            Some(Ok(mut field)) if UploadOptions::is_option(&field) => {
+                if let Err(response) = options.read(&mut field).await {
+                    return response;
                 }
             }
-            Err(_) => return HttpResponse::InternalServerError().finish(),
+            Some(field) => break field,
+            None => return HttpResponse::BadRequest().body("No file in the request."),
         }
+    };
+    // 文件之前已经得到token时先检查，避免接收注定被拒绝的文件
+    if token.is_some() {
+        api_token = match authorize(&data, token.as_deref(), Scope::Upload).await {
+            Ok(api_token) => api_token,
+            Err(response) => return response,
+        };
+        max_file_size = size_limit(max_file_size, api_token.as_ref());
+        authorized = true;
     }
-
-    let file_extension;
-
-    // 然后接收文件
-    match payload.next().await.unwrap() {
+    let max_file_size_str = format_file_size(max_file_size);
+    let mut file_size = 0;
+    match file_field {
         Ok(mut field) => {
             let cd = field.content_disposition();
             let file_name = cd.get_filename().unwrap_or("unknown");
+            original_name = cd.get_filename().map(str::to_string);
 
-            // 获取文件扩展名
+            // 获取文件扩展名，不能用在文件名中的扩展名视为未知
             file_extension = Path::new(file_name)
                 .extension()
                 .and_then(std::ffi::OsStr::to_str)
+                .filter(|extension| name::is_valid_extension(extension))
                 .unwrap_or("unknown")
                 .to_string();
-            // 检查上传模式
-            if *upload_mode == UploadMode::Whitelist {
-                if !upload_whitelist.contains(&file_extension) {
-                    error!("File extension {} not in whitelist.", &file_extension);
-                    return HttpResponse::BadRequest().body(format!(
-                        "You can't upload a file with extension {}, because the extension is not in whitelist.",
-                        &file_extension
-                    ));
-                }
-            } else if *upload_mode == UploadMode::Blacklist {
-                if upload_blacklist.contains(&file_extension) {
-                    error!("File extension {} in blacklist.", &file_extension);
-                    return HttpResponse::BadRequest().body(format!(
-                        "You can't upload a file with extension {}, because the extension is in blacklist.",
-                        &file_extension
-                    ));
+            let mut writer = match temp_file.reopen() {
+                Ok(f) => tokio::fs::File::from_std(f),
+                Err(e) => {
+                    error!("Error opening temp file: {}", e);
+                    return HttpResponse::InternalServerError().finish();
                 }
-            }
-
+            };
+            // 逐块写入临时文件，同时增量计算哈希，并留下文件头部用于识别类型
+            let mut header = Vec::with_capacity(sniff::SNIFF_LEN);
             while let Some(chunk) = field.next().await {
-                file_content.extend_from_slice(&chunk.unwrap());
-                let file_size = file_content.len();
+                let chunk = match chunk {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("Error receiving file: {}", e);
+                        return HttpResponse::BadRequest().body("Error receiving file.");
+                    }
+                };
+                file_size += chunk.len();
                 if file_size > max_file_size {
                     error!("The file size is too large, refused.");
                     let file_size_str = format_file_size(file_size);
@@ -344,42 +1086,243 @@ async fn upload_file(data: web::Data<AppState>, mut payload: Multipart) -> impl
                         &file_size_str, &max_file_size_str
                     ));
                 }
-                hasher.update(&file_content);
+                hasher.update(&chunk);
+                if header.len() < sniff::SNIFF_LEN {
+                    let take = chunk.len().min(sniff::SNIFF_LEN - header.len());
+                    header.extend_from_slice(&chunk[..take]);
+                }
+                if let Err(e) = writer.write_all(&chunk).await {
+                    error!("Error writing temp file: {}", e);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            }
+            if let Err(e) = writer.flush().await {
+                error!("Error writing temp file: {}", e);
+                return HttpResponse::InternalServerError().finish();
             }
-            let file_size = file_content.len();
             let file_size_str = format_file_size(file_size);
             info!("The file size is {}", &file_size_str);
+
+            // 根据魔数识别真实类型，不信任客户端提供的扩展名
+            let detected = sniff::detect(&header);
+            if !sniff::extension_matches(detected, &file_extension) {
+                match (&data.extension_mismatch, detected) {
+                    // 文件名没有扩展名时直接使用真实类型的扩展名
+                    (_, Some(file_type)) if file_extension == "unknown" => {
+                        file_extension = file_type.extension.to_string();
+                    }
+                    (MismatchAction::Rewrite, Some(file_type)) => {
+                        info!(
+                            "File extension {} doesn't match the content {}, rewritten to {}.",
+                            &file_extension, file_type.mime, file_type.extension
+                        );
+                        file_extension = file_type.extension.to_string();
+                    }
+                    _ => {
+                        let actual = detected.map_or("unknown", |file_type| file_type.mime);
+                        error!("File extension {} doesn't match the content {}.", &file_extension, actual);
+                        return HttpResponse::BadRequest().body(format!(
+                            "The file content ({}) doesn't match the extension {}.",
+                            actual, &file_extension
+                        ));
+                    }
+                }
+            }
+            mime_type = sniff::mime_type(detected, &file_extension);
+
+            // 检查上传模式，规则可以是扩展名或MIME类型
+            let matches_any = |rules: &[String]| {
+                rules
+                    .iter()
+                    .any(|rule| sniff::rule_matches(rule, &file_extension, &mime_type))
+            };
+            if *upload_mode == UploadMode::Whitelist && !matches_any(upload_whitelist) {
+                error!("File {} ({}) not in whitelist.", &file_extension, &mime_type);
+                return HttpResponse::BadRequest().body(format!(
+                    "You can't upload a file with extension {} ({}), because it is not in whitelist.",
+                    &file_extension, &mime_type
+                ));
+            } else if *upload_mode == UploadMode::Blacklist && matches_any(upload_blacklist) {
+                error!("File {} ({}) in blacklist.", &file_extension, &mime_type);
+                return HttpResponse::BadRequest().body(format!(
+                    "You can't upload a file with extension {} ({}), because it is in blacklist.",
+                    &file_extension, &mime_type
+                ));
+            }
+        }
+        Err(e) => {
+            error!("Error receiving form: {}", e);
+            return HttpResponse::BadRequest().body("Error receiving form.");
         }
-        Err(_) => return HttpResponse::InternalServerError().finish(),
     }
 
-    hasher.update(get_time().to_string().as_bytes());
+    // 文件之后的选项字段和token
+    while let Some(field) = payload.next().await {
+        match field {
+            Ok(mut field) if field.name() == "token" => match read_token(&mut field).await {
+                Some(value) => token = Some(value),
+                None => return unauthorized("Invalid token field."),
+            },
+            Ok(mut field) if UploadOptions::is_option(&field) => {
+                if let Err(response) = options.read(&mut field).await {
+                    return response;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Error receiving form: {}", e);
+                return HttpResponse::BadRequest().body("Error receiving form.");
+            }
+        }
+    }
+    // token在文件之后时，收完整个表单再检查，文件仍然要满足token的大小上限
+    if !authorized {
+        api_token = match authorize(&data, token.as_deref(), Scope::Upload).await {
+            Ok(api_token) => api_token,
+            Err(response) => return response,
+        };
+        let limit = size_limit(max_file_size, api_token.as_ref());
+        if file_size > limit {
+            error!("The file size is too large for the token, refused.");
+            return HttpResponse::BadRequest().body(format!(
+                "The file is too large ({}, expected less than {}).",
+                format_file_size(file_size),
+                format_file_size(limit)
+            ));
+        }
+    }
+    let uploaded_at = get_time();
+    let expires_at = expiry(options.expires_in, data.default_expires_in, data.max_expires_in, uploaded_at);
+    // 0表示不限制访问次数
+    let views_left = options.max_views.filter(|&views| views > 0);
+    // 会过期或限制访问次数的上传不参与去重，否则一次上传的限制会影响到共享同一个文件的其他上传
+    let dedup = data.dedup && expires_at.is_none() && views_left.is_none();
+
+    // 去重时文件名只由内容决定，否则混入当前时间，让相同内容的多次上传得到不同的文件
+    if !dedup {
+        hasher.update(get_time().to_string().as_bytes());
+    }
+    // 有限制的上传各自保存一份，同一秒内上传的相同内容也不能落到同一个文件上
+    if expires_at.is_some() || views_left.is_some() {
+        hasher.update(generate_key().as_bytes());
+    }
 
     let file_hash = hasher.finalize();
     let file_hash_str = format!("{:x}", file_hash);
     let shortened_file_hash_str = shorten(&file_hash_str);
 
-    // 构建文件保存路径
-    let file_name = format!("{}.{}", shortened_file_hash_str, file_extension);
-    let file_path = format!("{}/file/{}", www_root, file_name);
-    // 保存文件
-    let mut file = web::block(move || {
-        File::create(&file_path)
-            .map_err(|e| {
-                eprintln!("Error creating file: {:?}", e);
-                HttpResponse::InternalServerError().finish()
-            })
-            .unwrap()
-    })
-    .await
-    .unwrap();
-
-    match file.write_all(file_content.as_slice()) {
-        Ok(_) => (),
+    // 构建文件名并把临时文件放入存储
+    let file_name = match StoredName::from_hash(&shortened_file_hash_str, &file_extension) {
+        Ok(name) => name,
+        Err(msg) => {
+            error!("Error building file name: {}", msg);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let image_format = imaging::image_format(&file_name);
+    let strip_options = data.strip_metadata.filter(|_| image_format.is_some_and(metadata::is_strippable));
+    let process = data.auto_orient || strip_options.is_some();
+    // 图片需要处理、计算感知哈希和生成缩略图，在临时文件被移走之前读出内容
+    let mut image_content = match image_format {
+        Some(_) => {
+            match tokio::fs::read(temp_file.path()).await {
+                Ok(content) => Some(Bytes::from(content)),
+                Err(e) => {
+                    error!("Error reading temp file: {}", e);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            }
+        }
+        _ => None,
+    };
+    // 处理图片，并用处理后的内容覆盖临时文件
+    if let (Some(format), Some(content)) = (image_format.filter(|_| process), image_content.clone()) {
+        let auto_orient = data.auto_orient;
+        match web::block(move || process_image(&content, format, auto_orient, strip_options)).await {
+            Ok(Ok(Some(processed))) => {
+                if let Err(e) = tokio::fs::write(temp_file.path(), &processed).await {
+                    error!("Error writing temp file: {}", e);
+                    return HttpResponse::InternalServerError().finish();
+                }
+                info!("Processed image {}, {} after processing.", &file_name, format_file_size(processed.len()));
+                image_content = Some(processed);
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => {
+                error!("Error processing image {}: {}", &file_name, e);
+                return HttpResponse::BadRequest().body("The file is not a valid image.");
+            }
+            Err(e) => {
+                error!("Error processing image {}: {}", &file_name, e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+    // 按策略清理SVG中的脚本和外部引用，并用清理后的内容覆盖临时文件
+    let mime = new_mime_guess::from_ext(&file_extension).first_or_octet_stream();
+    if data.active_content.sanitize(&mime) {
+        let content = match tokio::fs::read(temp_file.path()).await {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Error reading temp file: {}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+        let sanitized = match web::block(move || svg::sanitize(&content)).await {
+            Ok(Ok(sanitized)) => sanitized,
+            Ok(Err(e)) => {
+                error!("Error sanitizing {}: {}", &file_name, e);
+                return HttpResponse::BadRequest().body("The file is not a valid SVG image.");
+            }
+            Err(e) => {
+                error!("Error sanitizing {}: {}", &file_name, e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+        if let Err(e) = tokio::fs::write(temp_file.path(), &sanitized).await {
+            error!("Error writing temp file: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+        info!("Sanitized {}, {} left.", &file_name, format_file_size(sanitized.len()));
+    }
+    let stored_size = match temp_file.as_file().metadata() {
+        Ok(metadata) => metadata.len(),
         Err(e) => {
-            eprintln!("Error writing file: {:?}", e);
+            error!("Error reading temp file: {}", e);
             return HttpResponse::InternalServerError().finish();
         }
+    };
+    // 去重时记录一次引用，文件已经存在时不必再写入
+    let exists = if dedup {
+        match data.store.acquire(&file_name).await {
+            Ok(1) => false,
+            Ok(_) => match data.storage.stat(&file_name).await {
+                Ok(_) => true,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+                Err(e) => {
+                    error!("Error reading file {}: {}", &file_name, e);
+                    let _ = data.store.release(&file_name).await;
+                    return HttpResponse::InternalServerError().finish();
+                }
+            },
+            Err(e) => {
+                error!("Error adding reference to {}: {}", &file_name, e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    } else {
+        false
+    };
+    if exists {
+        info!("File {} already exists, reference added.", &file_name);
+    } else if let Err(e) = data.storage.put_file(&file_name, temp_file).await {
+        error!("Error writing file {}: {}", &file_name, e);
+        if dedup {
+            let _ = data.store.release(&file_name).await;
+        }
+        return HttpResponse::InternalServerError().finish();
+    } else {
+        data.stats.add(stored_size);
     }
 
     // 返回URL（使用哈希值）
@@ -387,49 +1330,639 @@ async fn upload_file(data: web::Data<AppState>, mut payload: Multipart) -> impl
         true => "https".to_string(),
         false => "http".to_string(),
     };
-    let file_url = match proxy {
-        true => format!("{}://{}/{}", protocol, host, file_name),
-        false => format!("{}://{}:{}/{}", protocol, host, port, file_name),
+    let base_url = match proxy {
+        true => format!("{}://{}", protocol, host),
+        false => format!("{}://{}:{}", protocol, host, port),
     };
+    let file_url = format!("{}/{}", base_url, file_name);
+
+    // 每次上传生成一个删除密钥，只保存它的哈希
+    let delete_key = generate_key();
+    let delete_key = match data.store.add_delete_key(&file_name, &get_str_sha256(&delete_key)).await {
+        Ok(_) => Some(delete_key),
+        Err(e) => {
+            error!("Error saving delete key of {}: {}", &file_name, e);
+            None
+        }
+    };
+    let delete_url = delete_key
+        .as_ref()
+        .map(|key| format!("{}/delete/{}?key={}", base_url, file_name, key));
 
     info!("Upload file {} saved. URL is {}.", &file_name, &file_url);
 
-    HttpResponse::Ok().body(file_url)
+    // 计算感知哈希用于查找相似图片，同时生成缩略图并放入缓存，之后访问缩略图URL时直接命中缓存
+    let mut thumbnails = Vec::new();
+    let mut dimensions = None;
+    if let (Some(format), Some(content)) = (image_format, image_content) {
+        let transforms: Vec<Transform> = data.thumbnails.iter().map(|&size| Transform::thumbnail(size)).collect();
+        let analyzed = {
+            let transforms = transforms.clone();
+            web::block(move || {
+                let image = imaging::decode(&content, format)?;
+                let rendered = imaging::thumbnails(&image, format, &transforms)?;
+                let dimensions = (image.width(), image.height());
+                Ok::<_, image::ImageError>((dimensions, imaging::dhash(&image), rendered))
+            })
+            .await
+        };
+        match analyzed {
+            Ok(Ok((size, hash, rendered))) => {
+                dimensions = Some(size);
+                if let Err(e) = data.store.set_perceptual_hash(&file_name, hash).await {
+                    warn!("Error saving perceptual hash of {}: {}", &file_name, e);
+                }
+                for ((size, transform), content) in data.thumbnails.iter().zip(transforms).zip(rendered) {
+                    if let Err(e) = data.rendition_cache.put(&file_name, &transform.cache_key(format), Bytes::from(content)).await {
+                        warn!("Error caching thumbnail of {}: {}", &file_name, e);
+                    }
+                    thumbnails.push(ThumbnailUrl {
+                        size: *size,
+                        url: format!("{}?{}", &file_url, transform.query()),
+                    });
+                }
+                if !thumbnails.is_empty() {
+                    info!("Generated {} thumbnail(s) for {}.", thumbnails.len(), &file_name);
+                }
+            }
+            Ok(Err(e)) => warn!("File {} can't be decoded as an image, no thumbnails generated: {}", &file_name, e),
+            Err(e) => error!("Error generating thumbnails for {}: {}", &file_name, e),
+        }
+    }
+
+    // 记录上传信息，失败时文件仍然可用，下次启动时会补充一条不完整的记录
+    let record = FileRecord {
+        name: file_name.to_string(),
+        original_name,
+        uploader: req.connection_info().realip_remote_addr().map(str::to_string),
+        uploaded_at,
+        size: stored_size,
+        mime: mime_type,
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        expires_at,
+        views_left,
+        trashed_at: None,
+        token: api_token.map(|token| token.name),
+    };
+    if let Err(e) = data.store.insert_file(record).await {
+        warn!("Error saving upload record of {}: {}", &file_name, e);
+    }
+
+    // 默认只返回直链，与之前的客户端保持兼容；明确要求JSON时才返回完整的信息
+    if !wants_json(&req) {
+        let mut response = HttpResponse::Ok();
+        if let Some(delete_url) = &delete_url {
+            response.insert_header(("X-Delete-Url", delete_url.as_str()));
+        }
+        return response.body(file_url);
+    }
+    HttpResponse::Ok().json(UploadResponse {
+        url: file_url,
+        thumbnails,
+        expires_at,
+        max_views: views_left,
+        delete_key,
+        delete_url,
+    })
+}
+
+/// 客户端是否在`Accept`头中明确要求返回JSON
+fn wants_json(req: &HttpRequest) -> bool {
+    header::Accept::parse(req).is_ok_and(|accept| {
+        accept
+            .iter()
+            .any(|item| item.item == mime::APPLICATION_JSON && item.quality > header::Quality::ZERO)
+    })
+}
+
+/// # UploadOptions
+///
+/// 上传表单中文件以外的选项字段
+#[derive(Default)]
+struct UploadOptions {
+    /// 有效期，单位为秒
+    expires_in: Option<u64>,
+    /// 最多可以访问的次数
+    max_views: Option<u64>,
+}
+
+impl UploadOptions {
+    /// 字段是否是选项字段
+    fn is_option(field: &Field) -> bool {
+        matches!(field.name(), "expires_in" | "max_views")
+    }
+
+    /// 读取一个选项字段，格式错误时返回400响应
+    async fn read(&mut self, field: &mut Field) -> Result<(), HttpResponse> {
+        let value = read_number(field).await;
+        match field.name() {
+            "expires_in" => {
+                self.expires_in = Some(value.ok_or_else(|| {
+                    HttpResponse::BadRequest().body("expires_in must be a number of seconds.")
+                })?)
+            }
+            _ => {
+                self.max_views = Some(value.ok_or_else(|| {
+                    HttpResponse::BadRequest().body("max_views must be a number.")
+                })?)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 读取表单字段中的文本，读取失败、超过`max_len`字节或不是UTF-8时返回`None`
+async fn read_text(field: &mut Field, max_len: usize) -> Option<String> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|e| error!("Error receiving form: {}", e)).ok()?;
+        value.extend_from_slice(&chunk);
+        // 限制长度避免读入过大的字段
+        if value.len() > max_len {
+            return None;
+        }
+    }
+    String::from_utf8(value).ok()
+}
+
+/// 读取表单字段中的非负整数，读取失败或格式错误时返回`None`
+async fn read_number(field: &mut Field) -> Option<u64> {
+    read_text(field, 32).await?.trim().parse().ok()
+}
+
+/// 读取表单中的token字段，读取失败或为空时返回`None`
+async fn read_token(field: &mut Field) -> Option<String> {
+    let token = read_text(field, 256).await?.trim().to_string();
+    (!token.is_empty()).then_some(token)
+}
+
+/// token有自己的大小上限时，取它和`max_file_size`中较小的一个
+fn size_limit(max_file_size: usize, token: Option<&ApiToken>) -> usize {
+    token
+        .and_then(|token| token.max_file_size)
+        .map_or(max_file_size, |limit| max_file_size.min(limit))
+}
+
+/// # expiry
+///
+/// 根据上传时指定的有效期和配置计算过期时间，永不过期时返回`None`
+///
+/// ## 参数
+/// - `requested`: 上传时指定的有效期（秒），没有指定时使用`default`，为0时表示永不过期
+/// - `default`: 配置的默认有效期
+/// - `max`: 配置的最长有效期，为0时不限制
+/// - `now`: 当前时间
+fn expiry(requested: Option<u64>, default: u64, max: u64, now: u64) -> Option<u64> {
+    let expires_in = match (requested.unwrap_or(default), max) {
+        (0, 0) => return None,
+        (0, max) => max,
+        (expires_in, 0) => expires_in,
+        (expires_in, max) => expires_in.min(max),
+    };
+    Some(now.saturating_add(expires_in))
+}
+
+/// # process_image
+///
+/// 上传时对图片做的处理：先按EXIF中的方向旋转，再去除元数据。
+///
+/// 返回处理后的内容，没有做任何修改时返回`None`；图片无法解析时返回错误描述。
+fn process_image(
+    content: &Bytes,
+    format: ImageFormat,
+    auto_orient: bool,
+    strip_options: Option<StripOptions>,
+) -> Result<Option<Bytes>, String> {
+    let mut processed = None;
+    if auto_orient {
+        if let Some(rotated) = imaging::auto_orient(content, format).map_err(|e| e.to_string())? {
+            processed = Some(Bytes::from(rotated));
+        }
+    }
+    if let Some(options) = strip_options {
+        let current = processed.unwrap_or_else(|| content.clone());
+        processed = Some(metadata::strip(current, format, &options).map_err(|e| e.to_string())?);
+    }
+    Ok(processed)
+}
+
+/// 上传成功并且客户端要求JSON时返回的内容
+#[derive(Serialize)]
+struct UploadResponse {
+    url: String,
+    thumbnails: Vec<ThumbnailUrl>,
+    /// 过期时间，Unix时间戳，永不过期时不返回
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    /// 最多可以访问的次数，不限制时不返回
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_views: Option<u64>,
+    /// 删除文件时需要提供的密钥
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delete_key: Option<String>,
+    /// 带有删除密钥的删除URL，向它发送POST或DELETE请求即可删除文件
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delete_url: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ThumbnailUrl {
+    size: u32,
+    url: String,
+}
+
+/// # authorize
+///
+/// 检查请求提供的token是否允许进行`scope`操作，通过时返回token的信息
+///
+/// 没有开启`use_token`时，不提供token也可以通过；提供了token时总是要检查。
+///
+/// ## 参数
+/// - `token`: 请求中提供的token
+/// - `scope`: 要进行的操作
+async fn authorize(data: &AppState, token: Option<&str>, scope: Scope) -> Result<Option<ApiToken>, HttpResponse> {
+    match token {
+        None if !data.use_token => Ok(None),
+        token => require_token(data, token, scope).await.map(Some),
+    }
+}
+
+/// # require_token
+///
+/// 检查请求提供的token是否允许进行`scope`操作，通过时返回token的信息
+///
+/// 与`authorize`不同，无论是否开启`use_token`都必须提供token，用于回收站等只有管理者才能使用的接口。
+///
+/// ## 参数
+/// - `token`: 请求中提供的token
+/// - `scope`: 要进行的操作
+async fn require_token(data: &AppState, token: Option<&str>, scope: Scope) -> Result<ApiToken, HttpResponse> {
+    let Some(token) = token else {
+        return Err(unauthorized("A token is required."));
+    };
+    match data.tokens.authenticate(token).await {
+        Ok(Some(token)) if token.allows(scope) => Ok(token),
+        Ok(Some(token)) => {
+            warn!("Token {} is not allowed to {}.", &token.name, scope);
+            Err(HttpResponse::Forbidden().json(ErrorResponse {
+                error: format!("This token doesn't have the {} scope.", scope),
+            }))
+        }
+        Ok(None) => {
+            warn!("Incorrect token.");
+            Err(unauthorized("Incorrect token!"))
+        }
+        Err(e) => {
+            error!("Error checking token: {}", e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// token验证失败时返回的JSON
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// 没有提供token或token不正确时返回的401响应
+fn unauthorized(message: &str) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+        .json(ErrorResponse {
+            error: message.to_string(),
+        })
+}
+
+/// # request_token
+///
+/// 读取请求头中的token，支持`Authorization: Bearer <token>`和`X-Api-Key: <token>`两种形式
+fn request_token(req: &HttpRequest) -> Option<String> {
+    let headers = req.headers();
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+        .map(|(_, token)| token);
+    let api_key = || headers.get("X-Api-Key").and_then(|value| value.to_str().ok());
+    bearer
+        .or_else(api_key)
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+}
+
+/// 通过查询参数提供的token，用于GET请求，请求头中有token时优先使用请求头中的
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DuplicatesQuery {
+    /// 感知哈希之间的最大汉明距离
+    distance: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct DuplicatesResponse {
+    groups: Vec<Vec<String>>,
+}
+
+/// 查找相似图片时默认的最大汉明距离
+const DEFAULT_DUPLICATE_DISTANCE: u32 = 5;
+
+/// # list_duplicates
+///
+/// 按感知哈希把相似的图片分组返回，用`?distance=`指定最大汉明距离（0-64）。需要有`List`权限的token
+#[get("/api/duplicates")]
+async fn list_duplicates(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<DuplicatesQuery>,
+    auth: web::Query<TokenQuery>,
+) -> impl Responder {
+    let token = request_token(&req).or(auth.into_inner().token);
+    if let Err(response) = require_token(&data, token.as_deref(), Scope::List).await {
+        return response;
+    }
+    let distance = query.distance.unwrap_or(DEFAULT_DUPLICATE_DISTANCE);
+    if distance > 64 {
+        return HttpResponse::BadRequest().body("The distance must be between 0 and 64.");
+    }
+    let hashes = match data.store.perceptual_hashes().await {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            error!("Error reading perceptual hashes: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let groups = web::block(move || imaging::group_similar(&hashes, distance)).await;
+    match groups {
+        Ok(groups) => HttpResponse::Ok().json(DuplicatesResponse { groups }),
+        Err(e) => {
+            error!("Error grouping similar images: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # file_info
+///
+/// 返回文件的上传记录：原始文件名、上传者、上传时间、大小、MIME类型和图片尺寸。
+///
+/// 记录中有上传者的IP地址，无论是否开启`use_token`，都需要有`List`权限的token
+#[get("/api/files/{filename}")]
+async fn file_info(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    filename: web::Path<String>,
+    auth: web::Query<TokenQuery>,
+) -> impl Responder {
+    let token = request_token(&req).or(auth.into_inner().token);
+    if let Err(response) = require_token(&data, token.as_deref(), Scope::List).await {
+        return response;
+    }
+    let filename = match filename.parse::<StoredName>() {
+        Ok(name) => name,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    match data.store.file(&filename).await {
+        Ok(Some(record)) => HttpResponse::Ok().json(record),
+        Ok(None) => HttpResponse::NotFound().body(format!("{} not found", filename)),
+        Err(e) => {
+            error!("Error reading upload record of {}: {}", &filename, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # storage_stats
+///
+/// 以JSON返回存储中的文件数和总大小（字节）
+#[get("/api/stats")]
+async fn storage_stats(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.stats.snapshot())
+}
+
+#[derive(Serialize)]
+struct TrashResponse {
+    /// 文件在回收站中保留的时间，单位为秒
+    retention: u64,
+    files: Vec<FileRecord>,
+}
+
+/// # list_trash
+///
+/// 列出回收站中的文件，最近删除的在前。无论是否开启`use_token`，都需要有`List`权限的token
+#[get("/api/trash")]
+async fn list_trash(req: HttpRequest, data: web::Data<AppState>, auth: web::Query<TokenQuery>) -> impl Responder {
+    let token = request_token(&req).or(auth.into_inner().token);
+    if let Err(response) = require_token(&data, token.as_deref(), Scope::List).await {
+        return response;
+    }
+    let Some(trash) = &data.trash else {
+        return HttpResponse::NotFound().body("Trash is disabled.");
+    };
+    match trash.list().await {
+        Ok(files) => HttpResponse::Ok().json(TrashResponse {
+            retention: trash.retention(),
+            files,
+        }),
+        Err(e) => {
+            error!("Error listing trash: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # restore_file
+///
+/// 把文件从回收站中恢复。无论是否开启`use_token`，都需要有`Admin`权限的token
+#[post("/api/trash/restore")]
+async fn restore_file(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    req_body: web::Json<RestoreRequest>,
+) -> impl Responder {
+    let token = request_token(&req).or_else(|| req_body.token.clone());
+    if let Err(response) = require_token(&data, token.as_deref(), Scope::Admin).await {
+        return response;
+    }
+    let Some(trash) = &data.trash else {
+        return HttpResponse::NotFound().body("Trash is disabled.");
+    };
+    let filename = match req_body.filename.parse::<StoredName>() {
+        Ok(name) => name,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    match trash.restore(&filename).await {
+        Ok(restored) => {
+            if let Some(size) = restored {
+                data.stats.add(size);
+            }
+            info!("File {} restored from trash.", &filename);
+            HttpResponse::Ok().body(format!("{} restored", filename))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("File {} not found in trash.", &filename);
+            HttpResponse::NotFound().body(format!("{} not found in trash", filename))
+        }
+        Err(e) => {
+            error!("Error restoring file {}: {}", &filename, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RestoreRequest {
+    /// 要恢复的文件名，字段名与`/delete`一致
+    #[serde(rename = "file")]
+    filename: String,
+    /// 有`Admin`权限的token，也可以放在请求头中
+    token: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct DeleteRequest {
     file: String,
+    /// 上传时返回的删除密钥
+    key: Option<String>,
+    /// 有`Delete`权限的token，提供时不需要删除密钥
+    token: Option<String>,
 }
 
 #[post("/delete")]
 async fn delete_file(
+    req: HttpRequest,
     data: web::Data<AppState>,
     req_body: web::Json<DeleteRequest>,
 ) -> impl Responder {
-    let www_root = &data.www_root;
-    let filename = &req_body.file;
-
-    if filename.is_empty() {
-        return HttpResponse::BadRequest().body("Please do not send blank file name");
+    let req_body = req_body.into_inner();
+    let token = request_token(&req).or(req_body.token);
+    match req_body.file.parse::<StoredName>() {
+        Ok(name) => delete_stored_file(&data, &name, req_body.key, token).await,
+        Err(msg) => HttpResponse::BadRequest().body(msg),
     }
+}
 
-    let path = format!("{}/file/{}", www_root, filename);
+#[derive(Deserialize)]
+struct DeleteKeyQuery {
+    key: Option<String>,
+}
 
-    if Path::new(&path).exists() {
-        match fs::remove_file(&path) {
-            Ok(_) => {
-                info!("File {} deleted.", &filename);
-                return HttpResponse::Ok().body(format!("{} deleted", filename));
-            }
-            Err(err) => {
-                error!("Internal error when deleting file {}.", &filename);
-                return HttpResponse::InternalServerError()
-                    .body(format!("Error deleting file {}: {:?}", filename, err));
+/// # delete_file_by_url
+///
+/// 上传时返回的删除URL，删除密钥在查询参数中
+#[route("/delete/{filename}", method = "POST", method = "DELETE")]
+async fn delete_file_by_url(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    filename: web::Path<String>,
+    query: web::Query<DeleteKeyQuery>,
+) -> impl Responder {
+    match filename.parse::<StoredName>() {
+        Ok(name) => delete_stored_file(&data, &name, query.into_inner().key, request_token(&req)).await,
+        Err(msg) => HttpResponse::BadRequest().body(msg),
+    }
+}
+
+/// # delete_stored_file
+///
+/// 检查删除密钥或token，然后删除文件
+///
+/// 提供了token但token不正确时返回401，不会退回到检查删除密钥。
+///
+/// ## 参数
+/// - `filename`: 要删除的文件名
+/// - `key`: 上传时返回的删除密钥，每个密钥只能使用一次
+/// - `token`: 有`Delete`权限的token
+async fn delete_stored_file(
+    data: &AppState,
+    filename: &StoredName,
+    key: Option<String>,
+    token: Option<String>,
+) -> HttpResponse {
+    // 有删除权限的token可以删除任何文件，否则需要删除密钥
+    let mut scope_error = None;
+    if let Some(token) = token {
+        match require_token(data, Some(&token), Scope::Delete).await {
+            Ok(token) => {
+                info!("Deleting {} with token {}.", filename, &token.name);
+                return remove_reference(data, filename).await;
             }
+            // 没有删除权限的token仍然可以和删除密钥一起使用
+            Err(response) if response.status() == StatusCode::FORBIDDEN => scope_error = Some(response),
+            Err(response) => return response,
+        }
+    }
+    let Some(key) = key else {
+        return scope_error.unwrap_or_else(|| {
+            HttpResponse::Forbidden().json(ErrorResponse {
+                error: "A delete key is required.".to_string(),
+            })
+        });
+    };
+    match data.store.take_delete_key(filename, &get_str_sha256(&key)).await {
+        Ok(true) => remove_reference(data, filename).await,
+        Ok(false) => {
+            warn!("Invalid delete key for {}.", filename);
+            HttpResponse::Forbidden().json(ErrorResponse {
+                error: "Invalid delete key.".to_string(),
+            })
+        }
+        Err(e) => {
+            error!("Error checking delete key of {}: {}", filename, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # remove_reference
+///
+/// 删除一次上传的引用，最后一次引用被删除时删除文件
+///
+/// ## 参数
+/// - `filename`: 要删除的文件名
+async fn remove_reference(data: &AppState, filename: &StoredName) -> HttpResponse {
+    // 去重后同一个文件可能被上传了多次，最后一次引用被删除时才真正删除文件
+    match data.store.release(filename).await {
+        Ok(0) => {}
+        Ok(remaining) => {
+            info!("Reference to {} removed, {} remaining.", &filename, remaining);
+            return HttpResponse::Ok().body(format!("{} deleted", filename));
+        }
+        Err(e) => {
+            error!("Error removing reference to {}: {}", &filename, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    // 使用回收站时只是把文件移进去，保留期内还可以恢复
+    let deleted = match &data.trash {
+        Some(trash) => move_to_trash(data, trash, filename).await,
+        None => remove_file(data, filename).await,
+    };
+    match deleted {
+        Ok(_) => {
+            info!("File {} deleted.", &filename);
+            HttpResponse::Ok().body(format!("{} deleted", filename))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("File {} not fount when trying to delete it.", &filename);
+            HttpResponse::NotFound().body(format!("{} not found", filename))
+        }
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            warn!("Rejected deleting {}: {}", &filename, e);
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+        Err(err) => {
+            error!("Internal error when deleting file {}.", &filename);
+            HttpResponse::InternalServerError()
+                .body(format!("Error deleting file {}: {:?}", filename, err))
         }
-    } else {
-        warn!("File {} not fount when trying to delete it.", &filename);
-        return HttpResponse::NotFound().body(format!("{} not found", filename));
     }
 }
@@ -0,0 +1,468 @@
+use actix_web::web::Bytes;
+use exif::{In, Tag};
+use image::ImageFormat;
+use img_parts::{
+    jpeg::{markers, Jpeg, JpegSegment},
+    png::{Png, PngChunk},
+    riff::{RiffChunk, RiffContent},
+    webp::{WebP, CHUNK_EXIF, CHUNK_ICCP, CHUNK_VP8X, CHUNK_XMP},
+};
+
+/// JPEG的APP1段和WebP的EXIF块中，EXIF数据前可能带有的标识
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+
+/// JPEG的APP2段中ICC配置文件的标识
+const ICC_PREFIX: &[u8] = b"ICC_PROFILE\0";
+
+/// PNG的EXIF块
+const PNG_CHUNK_EXIF: [u8; 4] = *b"eXIf";
+
+/// PNG中携带元数据的块：EXIF、文本注释（XMP也存放在`iTXt`中）和修改时间
+const PNG_METADATA_CHUNKS: [[u8; 4]; 5] = [PNG_CHUNK_EXIF, *b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"];
+
+/// PNG的色彩配置文件块
+const PNG_CHUNK_ICCP: [u8; 4] = *b"iCCP";
+
+/// PNG的第一个图像数据块，重新写入的EXIF放在它之前
+const PNG_CHUNK_IDAT: [u8; 4] = *b"IDAT";
+
+/// VP8X块第一个字节中的标志位
+const VP8X_FLAG_ICC: u8 = 0x20;
+const VP8X_FLAG_EXIF: u8 = 0x08;
+const VP8X_FLAG_XMP: u8 = 0x04;
+
+/// # StripOptions
+///
+/// 去除元数据时需要保留的信息
+#[derive(Debug, Clone, Copy)]
+pub struct StripOptions {
+    /// 保留EXIF中的方向，否则依赖它的图片去除元数据后会显示为横躺的
+    pub keep_orientation: bool,
+    /// 保留ICC色彩配置文件，否则广色域的图片会偏色
+    pub keep_color_profile: bool,
+}
+
+/// 是否是可以去除元数据的图片格式
+pub fn is_strippable(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)
+}
+
+/// # strip
+///
+/// 去除图片中的EXIF、XMP、IPTC等元数据，不重新编码图像数据。
+///
+/// 保留方向时，只写回一份仅包含方向的最小EXIF。
+///
+/// ## 参数
+/// - `data`: 图片内容
+/// - `format`: 图片格式，不支持的格式原样返回
+/// - `options`: 需要保留的信息
+pub fn strip(data: Bytes, format: ImageFormat, options: &StripOptions) -> img_parts::Result<Bytes> {
+    match format {
+        ImageFormat::Jpeg => strip_jpeg(data, options),
+        ImageFormat::Png => strip_png(data, options),
+        ImageFormat::WebP => strip_webp(data, options),
+        _ => Ok(data),
+    }
+}
+
+fn strip_jpeg(data: Bytes, options: &StripOptions) -> img_parts::Result<Bytes> {
+    let mut jpeg = Jpeg::from_bytes(data)?;
+    let orientation = jpeg
+        .segments_by_marker(markers::APP1)
+        .find_map(|segment| segment.contents().strip_prefix(EXIF_PREFIX))
+        .and_then(orientation);
+
+    jpeg.segments_mut().retain(|segment| match segment.marker() {
+        // APP1是EXIF和XMP，APP13是IPTC，COM是注释
+        markers::APP1 | markers::APP13 | markers::COM => false,
+        // APP2中除了ICC配置文件，还可能是MPF等附带拍摄信息的数据
+        markers::APP2 => options.keep_color_profile && segment.contents().starts_with(ICC_PREFIX),
+        _ => true,
+    });
+
+    if let Some(orientation) = orientation.filter(|_| options.keep_orientation) {
+        let mut contents = EXIF_PREFIX.to_vec();
+        contents.extend_from_slice(&orientation_exif(orientation));
+        let segment = JpegSegment::new_with_contents(markers::APP1, Bytes::from(contents));
+        // EXIF应紧跟在SOI或JFIF的APP0之后
+        let position = match jpeg.segments().first() {
+            Some(first) if first.marker() == markers::APP0 => 1,
+            _ => 0,
+        };
+        jpeg.segments_mut().insert(position, segment);
+    }
+
+    Ok(jpeg.encoder().bytes())
+}
+
+fn strip_png(data: Bytes, options: &StripOptions) -> img_parts::Result<Bytes> {
+    let mut png = Png::from_bytes(data)?;
+    let orientation = png
+        .chunk_by_type(PNG_CHUNK_EXIF)
+        .and_then(|chunk| orientation(chunk.contents()));
+
+    png.chunks_mut().retain(|chunk| {
+        let kind = chunk.kind();
+        !PNG_METADATA_CHUNKS.contains(&kind) && (options.keep_color_profile || kind != PNG_CHUNK_ICCP)
+    });
+
+    if let Some(orientation) = orientation.filter(|_| options.keep_orientation) {
+        let chunk = PngChunk::new(PNG_CHUNK_EXIF, Bytes::from(orientation_exif(orientation)));
+        let position = png
+            .chunks()
+            .iter()
+            .position(|chunk| chunk.kind() == PNG_CHUNK_IDAT)
+            .unwrap_or(png.chunks().len());
+        png.chunks_mut().insert(position, chunk);
+    }
+
+    Ok(png.encoder().bytes())
+}
+
+fn strip_webp(data: Bytes, options: &StripOptions) -> img_parts::Result<Bytes> {
+    let mut webp = WebP::from_bytes(data)?;
+    // 只有扩展格式（带VP8X块）的WebP才能携带元数据
+    if !webp.has_chunk(CHUNK_VP8X) {
+        return Ok(webp.encoder().bytes());
+    }
+    let orientation = webp
+        .chunk_by_id(CHUNK_EXIF)
+        .and_then(|chunk| chunk.content().data())
+        .and_then(|data| orientation(data.strip_prefix(EXIF_PREFIX).unwrap_or(data)));
+
+    webp.chunks_mut().retain(|chunk| {
+        let id = chunk.id();
+        id != CHUNK_EXIF && id != CHUNK_XMP && (options.keep_color_profile || id != CHUNK_ICCP)
+    });
+
+    if let Some(orientation) = orientation.filter(|_| options.keep_orientation) {
+        let content = RiffContent::Data(Bytes::from(orientation_exif(orientation)));
+        webp.chunks_mut().push(RiffChunk::new(CHUNK_EXIF, content));
+    }
+
+    // 同步VP8X中标记各个可选块是否存在的标志位
+    let mut flags = 0;
+    if webp.has_chunk(CHUNK_ICCP) {
+        flags |= VP8X_FLAG_ICC;
+    }
+    if webp.has_chunk(CHUNK_EXIF) {
+        flags |= VP8X_FLAG_EXIF;
+    }
+    for chunk in webp.chunks_mut() {
+        if chunk.id() != CHUNK_VP8X {
+            continue;
+        }
+        if let Some(data) = chunk.content().data() {
+            let mut data = data.to_vec();
+            if let Some(first) = data.first_mut() {
+                *first = (*first & !(VP8X_FLAG_ICC | VP8X_FLAG_EXIF | VP8X_FLAG_XMP)) | flags;
+            }
+            *chunk.content_mut() = RiffContent::Data(Bytes::from(data));
+        }
+    }
+
+    Ok(webp.encoder().bytes())
+}
+
+/// 从TIFF格式的EXIF数据中读取方向，值为1（正常）或无法读取时返回`None`
+fn orientation(exif: &[u8]) -> Option<u16> {
+    let exif = exif::Reader::new().read_raw(exif.to_vec()).ok()?;
+    let value = exif.get_field(Tag::Orientation, In::PRIMARY)?.value.get_uint(0)?;
+    match value {
+        2..=8 => Some(value as u16),
+        _ => None,
+    }
+}
+
+/// 生成只包含方向的最小EXIF（小端序TIFF，IFD0中只有一项）
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut exif = Vec::with_capacity(26);
+    // TIFF头：字节序、魔数、IFD0的偏移
+    exif.extend_from_slice(b"II");
+    exif.extend_from_slice(&42u16.to_le_bytes());
+    exif.extend_from_slice(&8u32.to_le_bytes());
+    // IFD0：项数，然后是Orientation（0x0112），类型SHORT，数量1
+    exif.extend_from_slice(&1u16.to_le_bytes());
+    exif.extend_from_slice(&0x0112u16.to_le_bytes());
+    exif.extend_from_slice(&3u16.to_le_bytes());
+    exif.extend_from_slice(&1u32.to_le_bytes());
+    exif.extend_from_slice(&orientation.to_le_bytes());
+    exif.extend_from_slice(&[0, 0]);
+    // 没有下一个IFD
+    exif.extend_from_slice(&0u32.to_le_bytes());
+    exif
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, RgbImage};
+
+    use super::*;
+
+    const KEEP_ALL: StripOptions = StripOptions {
+        keep_orientation: true,
+        keep_color_profile: true,
+    };
+
+    const KEEP_NONE: StripOptions = StripOptions {
+        keep_orientation: false,
+        keep_color_profile: false,
+    };
+
+    const XMP: &[u8] = b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta><rdf:Description exif:GPSLatitude=\"51,30N\"/></x:xmpmeta>";
+
+    const ICC: &[u8] = b"not really an ICC profile";
+
+    /// 小端序的EXIF：IFD0中有方向和指向GPS IFD的指针，GPS IFD中有纬度的南北
+    fn exif_with_gps(orientation: u16) -> Vec<u8> {
+        let mut exif = Vec::new();
+        exif.extend_from_slice(b"II");
+        exif.extend_from_slice(&42u16.to_le_bytes());
+        exif.extend_from_slice(&8u32.to_le_bytes());
+        // IFD0，偏移8，两项，结束于8 + 2 + 2 * 12 + 4 = 38
+        exif.extend_from_slice(&2u16.to_le_bytes());
+        exif.extend_from_slice(&0x0112u16.to_le_bytes());
+        exif.extend_from_slice(&3u16.to_le_bytes());
+        exif.extend_from_slice(&1u32.to_le_bytes());
+        exif.extend_from_slice(&orientation.to_le_bytes());
+        exif.extend_from_slice(&[0, 0]);
+        exif.extend_from_slice(&0x8825u16.to_le_bytes());
+        exif.extend_from_slice(&4u16.to_le_bytes());
+        exif.extend_from_slice(&1u32.to_le_bytes());
+        exif.extend_from_slice(&38u32.to_le_bytes());
+        exif.extend_from_slice(&0u32.to_le_bytes());
+        // GPS IFD，一项GPSLatitudeRef = "N"
+        exif.extend_from_slice(&1u16.to_le_bytes());
+        exif.extend_from_slice(&0x0001u16.to_le_bytes());
+        exif.extend_from_slice(&2u16.to_le_bytes());
+        exif.extend_from_slice(&2u32.to_le_bytes());
+        exif.extend_from_slice(b"N\0\0\0");
+        exif.extend_from_slice(&0u32.to_le_bytes());
+        exif
+    }
+
+    fn encoded(format: ImageFormat) -> Bytes {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 2, image::Rgb([200, 100, 50])));
+        let mut data = Cursor::new(Vec::new());
+        image.write_to(&mut data, format).unwrap();
+        Bytes::from(data.into_inner())
+    }
+
+    fn exif_fields(exif: &[u8]) -> exif::Exif {
+        exif::Reader::new().read_raw(exif.to_vec()).unwrap()
+    }
+
+    fn assert_orientation_only(exif: &[u8], expected: u32) {
+        let exif = exif_fields(exif);
+        assert_eq!(exif.get_field(Tag::Orientation, In::PRIMARY).unwrap().value.get_uint(0), Some(expected));
+        assert!(exif.get_field(Tag::GPSLatitudeRef, In::PRIMARY).is_none());
+        assert_eq!(exif.fields().count(), 1);
+    }
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    fn sample_jpeg() -> Bytes {
+        let mut jpeg = Jpeg::from_bytes(encoded(ImageFormat::Jpeg)).unwrap();
+        let mut exif = EXIF_PREFIX.to_vec();
+        exif.extend_from_slice(&exif_with_gps(6));
+        let mut icc = ICC_PREFIX.to_vec();
+        icc.extend_from_slice(&[1, 1]);
+        icc.extend_from_slice(ICC);
+        let segments = [
+            (markers::APP1, exif),
+            (markers::APP1, XMP.to_vec()),
+            (markers::APP2, icc),
+            (markers::APP2, b"MPF\0camera data".to_vec()),
+            (markers::APP13, b"Photoshop 3.0\0IPTC".to_vec()),
+            (markers::COM, b"shot at home".to_vec()),
+        ];
+        for (i, (marker, contents)) in segments.into_iter().enumerate() {
+            let segment = JpegSegment::new_with_contents(marker, Bytes::from(contents));
+            jpeg.segments_mut().insert(1 + i, segment);
+        }
+        jpeg.encoder().bytes()
+    }
+
+    #[test]
+    fn samples_carry_gps() {
+        let exif = exif_fields(&exif_with_gps(6));
+        let field = exif.get_field(Tag::GPSLatitudeRef, In::PRIMARY).unwrap();
+        assert_eq!(field.display_value().to_string(), "N");
+        assert_eq!(orientation(&exif_with_gps(6)), Some(6));
+        for sample in [sample_jpeg(), sample_png(), sample_webp()] {
+            assert!(contains(&sample, b"xmpmeta"));
+            assert!(contains(&sample, b"N\0\0\0"));
+        }
+    }
+
+    #[test]
+    fn strips_jpeg_keeping_orientation_and_icc() {
+        let stripped = strip(sample_jpeg(), ImageFormat::Jpeg, &KEEP_ALL).unwrap();
+        image::load_from_memory_with_format(&stripped, ImageFormat::Jpeg).unwrap();
+        let jpeg = Jpeg::from_bytes(stripped.clone()).unwrap();
+
+        let app1: Vec<_> = jpeg.segments_by_marker(markers::APP1).collect();
+        assert_eq!(app1.len(), 1);
+        assert_orientation_only(app1[0].contents().strip_prefix(EXIF_PREFIX).unwrap(), 6);
+        let app2: Vec<_> = jpeg.segments_by_marker(markers::APP2).collect();
+        assert_eq!(app2.len(), 1);
+        assert!(app2[0].contents().starts_with(ICC_PREFIX));
+        assert_eq!(jpeg.segments_by_marker(markers::APP13).count(), 0);
+        assert_eq!(jpeg.segments_by_marker(markers::COM).count(), 0);
+        assert!(!contains(&stripped, b"xmpmeta"));
+        assert!(!contains(&stripped, b"camera data"));
+    }
+
+    #[test]
+    fn strips_jpeg_completely() {
+        let stripped = strip(sample_jpeg(), ImageFormat::Jpeg, &KEEP_NONE).unwrap();
+        image::load_from_memory_with_format(&stripped, ImageFormat::Jpeg).unwrap();
+        let jpeg = Jpeg::from_bytes(stripped.clone()).unwrap();
+        for marker in [markers::APP1, markers::APP2, markers::APP13, markers::COM] {
+            assert_eq!(jpeg.segments_by_marker(marker).count(), 0);
+        }
+        assert!(!contains(&stripped, ICC));
+    }
+
+    fn sample_png() -> Bytes {
+        let mut png = Png::from_bytes(encoded(ImageFormat::Png)).unwrap();
+        let mut xmp = b"XML:com.adobe.xmp\0\0\0\0\0".to_vec();
+        xmp.extend_from_slice(XMP);
+        let mut icc = b"icc\0\0".to_vec();
+        icc.extend_from_slice(ICC);
+        let chunks = [
+            (*b"iCCP", icc),
+            (PNG_CHUNK_EXIF, exif_with_gps(8)),
+            (*b"iTXt", xmp),
+            (*b"tEXt", b"Comment\0shot at home".to_vec()),
+            (*b"tIME", vec![7, 232, 1, 1, 0, 0, 0]),
+        ];
+        for (i, (kind, contents)) in chunks.into_iter().enumerate() {
+            png.chunks_mut().insert(1 + i, PngChunk::new(kind, Bytes::from(contents)));
+        }
+        png.encoder().bytes()
+    }
+
+    #[test]
+    fn strips_png_keeping_orientation_and_icc() {
+        let stripped = strip(sample_png(), ImageFormat::Png, &KEEP_ALL).unwrap();
+        let png = Png::from_bytes(stripped.clone()).unwrap();
+        assert_orientation_only(png.chunk_by_type(PNG_CHUNK_EXIF).unwrap().contents(), 8);
+        assert!(png.chunk_by_type(PNG_CHUNK_ICCP).is_some());
+        for kind in [*b"iTXt", *b"tEXt", *b"tIME"] {
+            assert!(png.chunk_by_type(kind).is_none());
+        }
+        // EXIF必须在图像数据之前
+        let kinds: Vec<_> = png.chunks().iter().map(|chunk| chunk.kind()).collect();
+        let exif = kinds.iter().position(|kind| *kind == PNG_CHUNK_EXIF).unwrap();
+        assert!(exif < kinds.iter().position(|kind| *kind == PNG_CHUNK_IDAT).unwrap());
+        assert!(!contains(&stripped, b"xmpmeta"));
+    }
+
+    #[test]
+    fn strips_png_completely() {
+        let stripped = strip(sample_png(), ImageFormat::Png, &KEEP_NONE).unwrap();
+        image::load_from_memory_with_format(&stripped, ImageFormat::Png).unwrap();
+        let png = Png::from_bytes(stripped).unwrap();
+        let kinds: Vec<_> = png.chunks().iter().map(|chunk| chunk.kind()).collect();
+        assert_eq!(kinds, vec![*b"IHDR", PNG_CHUNK_IDAT, *b"IEND"]);
+    }
+
+    /// 带VP8X块的扩展格式WebP，标志位中声明了ICC、EXIF和XMP
+    fn sample_webp() -> Bytes {
+        let mut webp = WebP::from_bytes(encoded(ImageFormat::WebP)).unwrap();
+        let mut vp8x = vec![VP8X_FLAG_ICC | VP8X_FLAG_EXIF | VP8X_FLAG_XMP, 0, 0, 0];
+        vp8x.extend_from_slice(&[3, 0, 0, 1, 0, 0]);
+        let mut exif = EXIF_PREFIX.to_vec();
+        exif.extend_from_slice(&exif_with_gps(3));
+        let chunk = |id, data: &[u8]| RiffChunk::new(id, RiffContent::Data(Bytes::copy_from_slice(data)));
+        webp.chunks_mut().insert(0, chunk(CHUNK_VP8X, &vp8x));
+        webp.chunks_mut().insert(1, chunk(CHUNK_ICCP, ICC));
+        webp.chunks_mut().push(chunk(CHUNK_EXIF, &exif));
+        webp.chunks_mut().push(chunk(CHUNK_XMP, XMP));
+        webp.encoder().bytes()
+    }
+
+    fn vp8x_flags(webp: &WebP) -> u8 {
+        webp.chunk_by_id(CHUNK_VP8X).unwrap().content().data().unwrap()[0]
+    }
+
+    #[test]
+    fn strips_webp_keeping_orientation_and_icc() {
+        let stripped = strip(sample_webp(), ImageFormat::WebP, &KEEP_ALL).unwrap();
+        image::load_from_memory_with_format(&stripped, ImageFormat::WebP).unwrap();
+        let webp = WebP::from_bytes(stripped).unwrap();
+        assert_eq!(vp8x_flags(&webp), VP8X_FLAG_ICC | VP8X_FLAG_EXIF);
+        assert!(webp.chunk_by_id(CHUNK_ICCP).is_some());
+        assert!(webp.chunk_by_id(CHUNK_XMP).is_none());
+        assert_orientation_only(webp.chunk_by_id(CHUNK_EXIF).unwrap().content().data().unwrap(), 3);
+    }
+
+    #[test]
+    fn strips_webp_completely() {
+        let stripped = strip(sample_webp(), ImageFormat::WebP, &KEEP_NONE).unwrap();
+        image::load_from_memory_with_format(&stripped, ImageFormat::WebP).unwrap();
+        let webp = WebP::from_bytes(stripped).unwrap();
+        assert_eq!(vp8x_flags(&webp), 0);
+        for id in [CHUNK_ICCP, CHUNK_EXIF, CHUNK_XMP] {
+            assert!(webp.chunk_by_id(id).is_none());
+        }
+    }
+
+    #[test]
+    fn leaves_simple_webp_alone() {
+        let simple = encoded(ImageFormat::WebP);
+        assert_eq!(strip(simple.clone(), ImageFormat::WebP, &KEEP_NONE).unwrap(), simple);
+    }
+
+    #[test]
+    fn orientation_exif_is_valid() {
+        for value in 1..=8 {
+            let exif = orientation_exif(value);
+            assert_eq!(exif.len(), 26);
+            let fields = exif_fields(&exif);
+            let field = fields.get_field(Tag::Orientation, In::PRIMARY).unwrap();
+            assert_eq!(field.value.get_uint(0), Some(value as u32));
+            assert_eq!(fields.fields().count(), 1);
+            let expected = (value != 1).then_some(value);
+            assert_eq!(orientation(&exif), expected);
+        }
+    }
+
+    #[test]
+    fn handles_corrupt_input() {
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP] {
+            assert!(strip(Bytes::from_static(b"not an image"), format, &KEEP_ALL).is_err());
+            assert!(strip(Bytes::new(), format, &KEEP_ALL).is_err());
+        }
+        for (format, data) in [
+            (ImageFormat::Jpeg, sample_jpeg()),
+            (ImageFormat::Png, sample_png()),
+            (ImageFormat::WebP, sample_webp()),
+        ] {
+            for len in [1, 8, 20, data.len() / 2, data.len() - 1] {
+                // 截断的文件可能被拒绝，也可能原样处理，但不能panic
+                let _ = strip(data.slice(..len), format, &KEEP_ALL);
+            }
+        }
+        assert_eq!(orientation(b""), None);
+        assert_eq!(orientation(b"II*\0garbage"), None);
+        assert_eq!(orientation(&exif_with_gps(9)), None);
+    }
+
+    #[test]
+    fn corrupt_exif_is_dropped() {
+        let mut jpeg = Jpeg::from_bytes(encoded(ImageFormat::Jpeg)).unwrap();
+        let mut exif = EXIF_PREFIX.to_vec();
+        exif.extend_from_slice(b"MM\0*garbage");
+        jpeg.segments_mut().insert(1, JpegSegment::new_with_contents(markers::APP1, Bytes::from(exif)));
+        let stripped = strip(jpeg.encoder().bytes(), ImageFormat::Jpeg, &KEEP_ALL).unwrap();
+        let jpeg = Jpeg::from_bytes(stripped).unwrap();
+        assert_eq!(jpeg.segments_by_marker(markers::APP1).count(), 0);
+    }
+}
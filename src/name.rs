@@ -0,0 +1,115 @@
+use std::{fmt, ops::Deref, str::FromStr};
+
+/// 文件名中哈希部分的最大长度，`shorten`输出的是一个128位数字的16进制形式
+const MAX_HASH_LEN: usize = 32;
+
+/// 扩展名的最大长度
+const MAX_EXTENSION_LEN: usize = 16;
+
+/// # StoredName
+///
+/// 经过检查的存储中的文件名，只接受服务器自己生成的`哈希.扩展名`形式，
+/// 其中哈希是小写的16进制数字，扩展名只含有ASCII字母和数字。
+///
+/// 这样的文件名中不会出现路径分隔符、`..`等字符，用户提供的文件名必须先转换为它才能访问存储。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredName(String);
+
+impl StoredName {
+    /// # from_hash
+    ///
+    /// 用上传时计算的哈希和扩展名构建文件名
+    ///
+    /// ## 参数
+    /// - `hash`: 缩短后的文件哈希
+    /// - `extension`: 文件扩展名，不含`.`
+    pub fn from_hash(hash: &str, extension: &str) -> Result<Self, String> {
+        format!("{}.{}", hash, extension).parse()
+    }
+}
+
+/// 扩展名是否可以用在服务器生成的文件名中
+pub fn is_valid_extension(extension: &str) -> bool {
+    !extension.is_empty()
+        && extension.len() <= MAX_EXTENSION_LEN
+        && extension.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+impl FromStr for StoredName {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let (hash, extension) = name
+            .split_once('.')
+            .ok_or_else(|| format!("{:?} is not a valid file name", name))?;
+        let valid_hash = !hash.is_empty()
+            && hash.len() <= MAX_HASH_LEN
+            && hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        if !valid_hash || !is_valid_extension(extension) {
+            return Err(format!("{:?} is not a valid file name", name));
+        }
+        Ok(Self(name.to_string()))
+    }
+}
+
+impl Deref for StoredName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for StoredName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_generated_names() {
+        for name in ["0123456789abcdef.png", "a.unknown", "5f6b02ba1c0a84bc10ef5b3496d9dc1a.JPG", "ff.mp4"] {
+            assert_eq!(name.parse::<StoredName>().unwrap().to_string(), name);
+        }
+        assert_eq!(&*StoredName::from_hash("abc", "webp").unwrap(), "abc.webp");
+    }
+
+    #[test]
+    fn rejects_paths() {
+        for name in ["../x.png", "..", ".", "/etc/passwd", "/abc.png", "a/b.png", "abc/def.png", "abc.png/", "abc\\def.png"] {
+            assert!(name.parse::<StoredName>().is_err(), "{:?} should be rejected", name);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_stems() {
+        let too_long = format!("{}.png", "a".repeat(MAX_HASH_LEN + 1));
+        for name in [".png", "", "abc", "ABC.png", "xyz.png", "ab c.png", "-1.png", &too_long] {
+            assert!(name.parse::<StoredName>().is_err(), "{:?} should be rejected", name);
+        }
+        let longest = format!("{}.png", "f".repeat(MAX_HASH_LEN));
+        assert!(longest.parse::<StoredName>().is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_extensions() {
+        let too_long = format!("abc.{}", "a".repeat(MAX_EXTENSION_LEN + 1));
+        for name in ["abc.", "abc.p.g", "abc.png ", "abc.pn-g", "abc.中文", "abc.png%2F", &too_long] {
+            assert!(name.parse::<StoredName>().is_err(), "{:?} should be rejected", name);
+        }
+        assert!(StoredName::from_hash("abc", "../png").is_err());
+        assert!(!is_valid_extension(""));
+        assert!(is_valid_extension(&"a".repeat(MAX_EXTENSION_LEN)));
+    }
+
+    #[test]
+    fn rejects_nul_bytes() {
+        for name in ["abc\0.png", "abc.png\0", "abc.p\0ng", "\0"] {
+            assert!(name.parse::<StoredName>().is_err(), "{:?} should be rejected", name);
+        }
+    }
+}
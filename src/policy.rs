@@ -0,0 +1,68 @@
+use mime::Mime;
+use serde_derive::{Deserialize, Serialize};
+
+/// 以附件形式返回的文件使用的CSP，禁止加载任何资源并把文档放进沙箱
+const ATTACHMENT_CSP: &str = "default-src 'none'; sandbox";
+
+/// 清理过的SVG使用的CSP，只允许内联样式和`data:`图片，作为清理之外的又一层保护
+const SVG_CSP: &str = "default-src 'none'; style-src 'unsafe-inline'; img-src data:; sandbox";
+
+/// # ActiveContentPolicy
+///
+/// 存储中可能包含脚本的文件（HTML、SVG等）的处理策略。
+///
+/// 这些文件和服务本身同源，如果直接在浏览器中打开，其中的脚本可以以本服务的身份运行。
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ActiveContentPolicy {
+    /// 按原本的类型直接返回
+    Allow,
+    /// 以附件形式返回，并用CSP禁止其中的脚本运行
+    #[default]
+    Attachment,
+    /// 上传时清理SVG，之后SVG可以直接显示；其他类型仍以附件形式返回
+    Sanitize,
+}
+
+impl ActiveContentPolicy {
+    /// 访问`mime`类型的文件时是否以附件形式返回
+    pub fn attachment(&self, mime: &Mime) -> bool {
+        match self {
+            ActiveContentPolicy::Allow => false,
+            ActiveContentPolicy::Attachment => is_active(mime),
+            ActiveContentPolicy::Sanitize => is_active(mime) && !is_svg(mime),
+        }
+    }
+
+    /// 访问`mime`类型的文件时返回的`Content-Security-Policy`，不需要时返回`None`
+    pub fn content_security_policy(&self, mime: &Mime) -> Option<&'static str> {
+        if *self == ActiveContentPolicy::Allow || !is_active(mime) {
+            None
+        } else if self.attachment(mime) {
+            Some(ATTACHMENT_CSP)
+        } else {
+            Some(SVG_CSP)
+        }
+    }
+
+    /// 上传`mime`类型的文件时是否需要先清理
+    pub fn sanitize(&self, mime: &Mime) -> bool {
+        *self == ActiveContentPolicy::Sanitize && is_svg(mime)
+    }
+}
+
+/// 浏览器直接打开时可能执行脚本的类型
+fn is_active(mime: &Mime) -> bool {
+    matches!(
+        (mime.type_().as_str(), mime.subtype().as_str()),
+        ("text", "html")
+            | ("text", "xml")
+            | ("text", "javascript")
+            | ("application", "xml")
+            | ("application", "xhtml")
+            | ("application", "javascript")
+    ) || mime.suffix() == Some(mime::XML)
+}
+
+fn is_svg(mime: &Mime) -> bool {
+    mime.essence_str() == "image/svg+xml"
+}
@@ -0,0 +1,70 @@
+/// 识别文件类型时读取的文件头部长度
+pub const SNIFF_LEN: usize = 8192;
+
+/// # FileType
+///
+/// 根据文件头部的魔数识别出的真实类型
+#[derive(Debug, Clone, Copy)]
+pub struct FileType {
+    pub mime: &'static str,
+    pub extension: &'static str,
+}
+
+/// 根据文件头部的魔数识别文件类型，无法识别时返回`None`
+pub fn detect(header: &[u8]) -> Option<FileType> {
+    infer::get(header).map(|kind| FileType {
+        mime: kind.mime_type(),
+        extension: kind.extension(),
+    })
+}
+
+/// # extension_matches
+///
+/// 检查扩展名是否与识别出的类型一致。
+///
+/// 无法识别类型时，只要扩展名对应的不是本可以通过魔数识别的类型（例如图片），就认为一致；
+/// 文本类的类型（HTML、XML）只能识别出带有特征标签的文件，因此不要求一定能识别出来。
+pub fn extension_matches(detected: Option<FileType>, extension: &str) -> bool {
+    let extension = extension.to_lowercase();
+    match detected {
+        Some(file_type) => {
+            // 只能识别出通用的XML，SVG等基于XML的格式也算一致
+            let is_xml = file_type.mime == "text/xml";
+            file_type.extension == extension
+                || new_mime_guess::from_ext(&extension).iter().any(|mime| {
+                    mime.essence_str() == file_type.mime
+                        || (is_xml && (mime.subtype() == mime::XML || mime.suffix() == Some(mime::XML)))
+                })
+        }
+        None => {
+            let mime = new_mime_guess::from_ext(&extension).first_or_octet_stream();
+            !infer::is_supported(&extension) || mime.type_() == mime::TEXT
+        }
+    }
+}
+
+/// 文件的MIME类型，优先使用识别出的类型，否则根据扩展名猜测
+pub fn mime_type(detected: Option<FileType>, extension: &str) -> String {
+    match detected {
+        Some(file_type) => file_type.mime.to_string(),
+        None => new_mime_guess::from_ext(extension)
+            .first_or_octet_stream()
+            .essence_str()
+            .to_string(),
+    }
+}
+
+/// # rule_matches
+///
+/// 检查上传白名单、黑名单中的一条规则是否匹配文件。
+///
+/// 包含`/`的规则是MIME类型，可以用`image/*`匹配一整类；否则是扩展名。
+pub fn rule_matches(rule: &str, extension: &str, mime: &str) -> bool {
+    match rule.split_once('/') {
+        Some((type_, "*")) => mime
+            .split_once('/')
+            .is_some_and(|(mime_type, _)| mime_type.eq_ignore_ascii_case(type_)),
+        Some(_) => mime.eq_ignore_ascii_case(rule),
+        None => extension.eq_ignore_ascii_case(rule),
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_derive::Serialize;
+
+/// # StorageStats
+///
+/// 存储中的文件数和总大小，由上传、删除增量更新，避免每次访问都遍历存储。
+///
+/// 计数可能因为并发或异常而与存储不一致，需要定期用`set`以实际扫描的结果校正。
+#[derive(Debug, Default)]
+pub struct StorageStats {
+    count: AtomicU64,
+    size: AtomicU64,
+}
+
+/// 某一时刻的统计数据
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct StatsSnapshot {
+    /// 文件数
+    pub count: u64,
+    /// 总大小，单位为字节
+    pub total_size: u64,
+}
+
+impl StorageStats {
+    pub fn new(count: u64, size: u64) -> Self {
+        Self {
+            count: AtomicU64::new(count),
+            size: AtomicU64::new(size),
+        }
+    }
+
+    /// 记录新存入了一个大小为`size`的文件
+    pub fn add(&self, size: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.size.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// 记录删除了一个大小为`size`的文件，计数不会减到0以下
+    pub fn remove(&self, size: u64) {
+        let _ = self
+            .count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| Some(count.saturating_sub(1)));
+        let _ = self
+            .size
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| Some(total.saturating_sub(size)));
+    }
+
+    /// 用扫描存储得到的结果覆盖计数
+    pub fn set(&self, count: u64, size: u64) {
+        self.count.store(count, Ordering::Relaxed);
+        self.size.store(size, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            total_size: self.size.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use std::{
+    any::Any,
+    io::{self, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+
+use actix_web::web::{self, Bytes};
+use async_trait::async_trait;
+use futures_util::stream::StreamExt;
+use log::{info, warn};
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use super::{copy_then_delete, ByteStream, FileStat, Storage};
+
+/// 每一级子目录的名称取文件名中的字符数
+const SHARD_WIDTH: usize = 2;
+
+/// # LocalStorage
+///
+/// 把文件存放在本地目录下的存储后端，也是默认的后端。
+///
+/// `shard_levels`为0时所有文件平铺在同一个目录下；大于0时按文件名的前几个字符分散到多级子目录中，
+/// 例如2级时`abcdef.png`存放在`ab/cd/abcdef.png`，避免单个目录中的文件过多。
+///
+/// 所有文件的路径都会被规范化并检查，不能指向存储目录之外。
+pub struct LocalStorage {
+    root: PathBuf,
+    shard_levels: usize,
+}
+
+impl LocalStorage {
+    /// 以`root`为存储目录创建平铺的后端，目录不存在时会自动创建
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        Self::sharded(root, 0)
+    }
+
+    /// 以`root`为存储目录创建按`shard_levels`级子目录分散文件的后端，目录不存在时会自动创建
+    pub fn sharded(root: impl Into<PathBuf>, shard_levels: usize) -> io::Result<Self> {
+        let root = root.into();
+        if !root.exists() {
+            warn!("File storage path {} not exists, making it.", root.display());
+            std::fs::create_dir_all(&root)?;
+        }
+        // 使用规范化的路径，之后才能判断文件是否位于存储目录之下
+        let root = root.canonicalize()?;
+        Ok(Self { root, shard_levels })
+    }
+
+    /// # path_of
+    ///
+    /// 文件`name`在存储目录中的路径。
+    ///
+    /// `name`只能是单独的一个文件名；路径中已经存在的部分会被规范化（解析符号链接等），
+    /// 结果不在存储目录之下时返回`InvalidInput`错误。
+    async fn path_of(&self, name: &str) -> io::Result<PathBuf> {
+        let mut components = Path::new(name).components();
+        if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+            return Err(invalid_name(name));
+        }
+        let path = shard_path(&self.root, self.shard_levels, name);
+        // 写入时文件和子目录可能还不存在，检查最深的已存在的一级
+        for existing in path.ancestors() {
+            match tokio::fs::canonicalize(existing).await {
+                Ok(resolved) if resolved.starts_with(&self.root) => return Ok(path),
+                Ok(_) => return Err(invalid_name(name)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(invalid_name(name))
+    }
+
+    /// # migrate
+    ///
+    /// 把存储目录中的所有文件移动到当前布局下应在的位置，并删除移空的子目录，返回移动的文件数。
+    ///
+    /// 可以把平铺的目录转换为多级子目录，也可以在修改`shard_levels`后重新整理。迁移时服务不应运行。
+    pub async fn migrate(&self) -> io::Result<usize> {
+        let root = self.root.clone();
+        let shard_levels = self.shard_levels;
+        web::block(move || {
+            let mut moved = 0;
+            for path in walk_files(&root)? {
+                let name = match path.file_name().and_then(|name| name.to_str()) {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let target = shard_path(&root, shard_levels, name);
+                if target == path {
+                    continue;
+                }
+                if target.exists() {
+                    warn!("{} already exists, {} is not moved.", target.display(), path.display());
+                    continue;
+                }
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::rename(&path, &target)?;
+                info!("Moved {} to {}.", path.display(), target.display());
+                moved += 1;
+            }
+            remove_empty_dirs(&root)?;
+            Ok(moved)
+        })
+        .await
+        .map_err(blocking_error)?
+    }
+}
+
+/// 文件`name`在`shard_levels`级布局下的路径
+///
+/// 文件名不够长或前缀中有字母、数字以外的字符时，仍然直接放在根目录下。
+fn shard_path(root: &Path, shard_levels: usize, name: &str) -> PathBuf {
+    let prefix_len = shard_levels * SHARD_WIDTH;
+    let stem = name.split('.').next().unwrap_or_default();
+    if shard_levels == 0
+        || stem.len() < prefix_len
+        || !stem.bytes().take(prefix_len).all(|b| b.is_ascii_alphanumeric())
+    {
+        return root.join(name);
+    }
+    let mut path = root.to_path_buf();
+    for level in 0..shard_levels {
+        path.push(&stem[level * SHARD_WIDTH..(level + 1) * SHARD_WIDTH]);
+    }
+    path.join(name)
+}
+
+/// 列出存储目录中的文件
+///
+/// 平铺时只看根目录，不把子目录中的文件算进来。
+fn stored_files(root: &Path, shard_levels: usize) -> io::Result<Vec<PathBuf>> {
+    if shard_levels > 0 {
+        return walk_files(root);
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// 递归列出目录下的所有文件
+fn walk_files(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// 删除`dir`下所有的空子目录，`dir`本身保留
+fn remove_empty_dirs(dir: &Path) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let path = entry.path();
+            remove_empty_dirs(&path)?;
+            if std::fs::read_dir(&path)?.next().is_none() {
+                std::fs::remove_dir(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 写入之前创建文件所在的子目录
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => std::fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+/// # copy_into
+///
+/// 把`source`复制到`target`所在目录中的临时文件，写完后再重命名为`target`，
+/// 不会让其他人读到复制了一半的文件。
+fn copy_into(source: &Path, target: &Path) -> io::Result<()> {
+    let parent = target.parent().unwrap_or_else(|| Path::new("."));
+    let mut temp_file = NamedTempFile::new_in(parent)?;
+    io::copy(&mut std::fs::File::open(source)?, temp_file.as_file_mut())?;
+    temp_file.as_file().sync_all()?;
+    temp_file.persist(target).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// 文件名不合法或指向存储目录之外时返回的错误
+fn invalid_name(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is outside of the storage", name))
+}
+
+/// 把`web::block`的线程池错误转换为IO错误
+fn blocking_error(e: actix_web::error::BlockingError) -> io::Error {
+    io::Error::other(e)
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put_file(&self, name: &str, file: NamedTempFile) -> io::Result<()> {
+        let path = self.path_of(name).await?;
+        web::block(move || {
+            create_parent(&path)?;
+            match file.persist(&path) {
+                Ok(_) => Ok(()),
+                // 临时目录和存储目录不在同一个文件系统上时无法重命名，先复制到存储目录中再重命名
+                Err(e) if e.error.kind() == io::ErrorKind::CrossesDevices => copy_into(e.file.path(), &path),
+                Err(e) => Err(e.error),
+            }
+        })
+        .await
+        .map_err(blocking_error)?
+    }
+
+    async fn get(&self, name: &str) -> io::Result<Bytes> {
+        let path = self.path_of(name).await?;
+        web::block(move || std::fs::read(path).map(Bytes::from))
+            .await
+            .map_err(blocking_error)?
+    }
+
+    async fn stream(&self, name: &str) -> io::Result<ByteStream> {
+        let file = tokio::fs::File::open(self.path_of(name).await?).await?;
+        Ok(ReaderStream::new(file).boxed())
+    }
+
+    async fn stream_range(&self, name: &str, start: u64, end: u64) -> io::Result<ByteStream> {
+        let mut file = tokio::fs::File::open(self.path_of(name).await?).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(ReaderStream::new(file.take(end - start + 1)).boxed())
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        // 变空的子目录保留下来：删除它会和正在向其中写入文件的上传竞争，子目录的数量本身也是有限的
+        tokio::fs::remove_file(self.path_of(name).await?).await
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let root = self.root.clone();
+        let shard_levels = self.shard_levels;
+        web::block(move || {
+            Ok(stored_files(&root, shard_levels)?
+                .iter()
+                .filter_map(|path| path.file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .collect())
+        })
+        .await
+        .map_err(blocking_error)?
+    }
+
+    async fn list_with_sizes(&self) -> io::Result<Vec<(String, u64)>> {
+        let root = self.root.clone();
+        let shard_levels = self.shard_levels;
+        web::block(move || {
+            let mut files = Vec::new();
+            for path in stored_files(&root, shard_levels)? {
+                let Some(name) = path.file_name() else {
+                    continue;
+                };
+                match std::fs::metadata(&path) {
+                    Ok(metadata) => {
+                        files.push((name.to_string_lossy().into_owned(), metadata.len()))
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(files)
+        })
+        .await
+        .map_err(blocking_error)?
+    }
+
+    async fn stat(&self, name: &str) -> io::Result<FileStat> {
+        let metadata = tokio::fs::metadata(self.path_of(name).await?).await?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "not a file"));
+        }
+        Ok(FileStat {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+
+    /// 目标也是本地存储时直接重命名，不在同一个文件系统上时先复制到目标目录中再重命名
+    async fn move_to(&self, name: &str, target: &dyn Storage) -> io::Result<()> {
+        let Some(target) = target.as_any().downcast_ref::<LocalStorage>() else {
+            return copy_then_delete(self, name, target).await;
+        };
+        let source = self.path_of(name).await?;
+        let path = target.path_of(name).await?;
+        web::block(move || {
+            // 源文件不存在时不要在目标中创建子目录
+            std::fs::symlink_metadata(&source)?;
+            create_parent(&path)?;
+            match std::fs::rename(&source, &path) {
+                Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                    copy_into(&source, &path)?;
+                    std::fs::remove_file(&source)
+                }
+                result => result,
+            }
+        })
+        .await
+        .map_err(blocking_error)?
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    fn storage(shard_levels: usize) -> (tempfile::TempDir, LocalStorage) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::sharded(dir.path().join("file"), shard_levels).unwrap();
+        (dir, storage)
+    }
+
+    fn temp_file(content: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        io::Write::write_all(&mut file, content).unwrap();
+        file
+    }
+
+    #[actix_web::test]
+    async fn path_of_stays_under_root() {
+        for shard_levels in [0, 2] {
+            let (_dir, storage) = storage(shard_levels);
+            let path = storage.path_of("abcdef.png").await.unwrap();
+            assert!(path.starts_with(&storage.root));
+            assert_eq!(path.file_name().unwrap(), "abcdef.png");
+        }
+        let (_dir, storage) = storage(2);
+        assert_eq!(
+            storage.path_of("abcdef.png").await.unwrap(),
+            storage.root.join("ab").join("cd").join("abcdef.png")
+        );
+    }
+
+    #[actix_web::test]
+    async fn path_of_rejects_other_paths() {
+        let (_dir, storage) = storage(0);
+        for name in ["../x.png", "..", ".", "", "/etc/passwd", "a/b.png", "x.png/..", "abc\0.png"] {
+            let err = storage.path_of(name).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?} should be rejected", name);
+        }
+    }
+
+    #[actix_web::test]
+    async fn path_of_rejects_symlinks_out_of_root() {
+        let (dir, storage) = storage(0);
+        let outside = dir.path().join("secret.txt");
+        std::fs::write(&outside, "secret").unwrap();
+        symlink(&outside, storage.root.join("abc.txt")).unwrap();
+
+        let err = storage.path_of("abc.txt").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(storage.get("abc.txt").await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(storage.delete("abc.txt").await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(outside.exists());
+    }
+
+    #[actix_web::test]
+    async fn path_of_rejects_symlinked_shard_directories() {
+        let (dir, storage) = storage(1);
+        let outside = dir.path().join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        symlink(&outside, storage.root.join("ab")).unwrap();
+
+        let err = storage.put_file("abcdef.png", temp_file(b"x")).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(std::fs::read_dir(&outside).unwrap().next().is_none());
+    }
+
+    #[actix_web::test]
+    async fn path_of_allows_symlinks_inside_root() {
+        let (_dir, storage) = storage(0);
+        std::fs::write(storage.root.join("target.txt"), "inside").unwrap();
+        symlink(storage.root.join("target.txt"), storage.root.join("abc.txt")).unwrap();
+        assert_eq!(storage.get("abc.txt").await.unwrap(), Bytes::from_static(b"inside"));
+    }
+
+    #[actix_web::test]
+    async fn symlinked_root_is_resolved() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real");
+        std::fs::create_dir(&real).unwrap();
+        symlink(&real, dir.path().join("link")).unwrap();
+        let storage = LocalStorage::new(dir.path().join("link")).unwrap();
+
+        storage.put_file("abc.png", temp_file(b"x")).await.unwrap();
+        assert!(real.join("abc.png").exists());
+        assert!(storage.path_of("abc.png").await.unwrap().starts_with(real.canonicalize().unwrap()));
+    }
+
+    #[actix_web::test]
+    async fn move_to_renames_into_other_local_storage() {
+        let (dir, storage) = storage(2);
+        let trash = LocalStorage::new(dir.path().join("trash")).unwrap();
+        storage.put_file("abcdef.png", temp_file(b"content")).await.unwrap();
+
+        storage.move_to("abcdef.png", &trash).await.unwrap();
+        assert_eq!(storage.stat("abcdef.png").await.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(trash.get("abcdef.png").await.unwrap(), Bytes::from_static(b"content"));
+
+        trash.move_to("abcdef.png", &storage).await.unwrap();
+        assert!(storage.root.join("ab").join("cd").join("abcdef.png").exists());
+        assert!(trash.list().await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn move_to_missing_file_changes_nothing() {
+        let (dir, storage) = storage(0);
+        let trash = LocalStorage::sharded(dir.path().join("trash"), 2).unwrap();
+        let err = storage.move_to("abcdef.png", &trash).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(std::fs::read_dir(&trash.root).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn copy_into_replaces_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        std::fs::write(&source, "new").unwrap();
+        std::fs::write(&target, "old").unwrap();
+        copy_into(&source, &target).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[actix_web::test]
+    async fn delete_keeps_shard_directories() {
+        let (_dir, storage) = storage(2);
+        storage.put_file("abcdef.png", temp_file(b"x")).await.unwrap();
+        storage.delete("abcdef.png").await.unwrap();
+        assert!(storage.root.join("ab").join("cd").is_dir());
+        storage.put_file("abcd01.png", temp_file(b"y")).await.unwrap();
+        assert_eq!(storage.list().await.unwrap(), vec!["abcd01.png"]);
+    }
+}
@@ -0,0 +1,170 @@
+mod local;
+mod s3;
+
+use std::{any::Any, io, path::Path, pin::Pin, sync::Arc, time::SystemTime};
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures_util::stream::{Stream, StreamExt};
+use serde_derive::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::Config;
+
+pub use local::LocalStorage;
+pub use s3::{S3Config, S3Storage};
+
+/// 存储后端返回的字节流
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// 可选的存储后端
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum StorageBackend {
+    /// 存放在`www_root/file`下，可以按`shard_levels`分散到多级子目录
+    #[default]
+    Local,
+    /// S3兼容的对象存储，需要在配置文件中提供`[s3]`表
+    S3,
+}
+
+/// # FileStat
+///
+/// 存储中某个文件的基本信息
+#[derive(Debug, Clone)]
+pub struct FileStat {
+    /// 文件大小，单位为字节
+    pub size: u64,
+    /// 最后修改时间，后端无法提供时为`None`
+    pub modified: Option<SystemTime>,
+}
+
+/// # Storage
+///
+/// 文件存储后端的统一接口。
+///
+/// 所有的`name`都是存储内的文件名（例如`abcdef.png`），不包含任何目录前缀，
+/// 如何把它映射到实际位置由后端自己决定。文件不存在时应返回`io::ErrorKind::NotFound`。
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// 把一个已经写好的临时文件放入存储，已存在时覆盖
+    ///
+    /// 文件要么完整地出现在存储中，要么完全不出现，不会被读到写了一半的内容。
+    async fn put_file(&self, name: &str, file: NamedTempFile) -> io::Result<()>;
+
+    /// 读取整个文件
+    async fn get(&self, name: &str) -> io::Result<Bytes>;
+
+    /// 以流的形式读取文件
+    async fn stream(&self, name: &str) -> io::Result<ByteStream>;
+
+    /// 以流的形式读取文件中`start..=end`范围内的字节
+    async fn stream_range(&self, name: &str, start: u64, end: u64) -> io::Result<ByteStream>;
+
+    /// 删除一个文件
+    async fn delete(&self, name: &str) -> io::Result<()>;
+
+    /// 列出存储中的所有文件名
+    async fn list(&self) -> io::Result<Vec<String>>;
+
+    /// 列出存储中的所有文件名及其大小
+    ///
+    /// 默认对每个文件调用一次`stat`，列出之后被删除的文件会被跳过；
+    /// 能在列目录时直接拿到大小的后端应当覆盖这个方法。
+    async fn list_with_sizes(&self) -> io::Result<Vec<(String, u64)>> {
+        let mut files = Vec::new();
+        for name in self.list().await? {
+            match self.stat(&name).await {
+                Ok(stat) => files.push((name, stat.size)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(files)
+    }
+
+    /// 获取文件信息
+    async fn stat(&self, name: &str) -> io::Result<FileStat>;
+
+    /// 把文件移动到另一个存储（例如回收站）中
+    ///
+    /// 默认经过临时文件复制再删除；后端可以在目标是同类存储时改用重命名或服务端复制。
+    async fn move_to(&self, name: &str, target: &dyn Storage) -> io::Result<()> {
+        copy_then_delete(self, name, target).await
+    }
+
+    /// 获取可以直接访问文件的临时URL
+    ///
+    /// 返回`Some`时，访问文件的请求会被重定向到这个URL，而不是由本服务转发文件内容。
+    async fn presigned_url(&self, _name: &str) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// 用于在`move_to`中判断目标存储的具体类型
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// # download
+///
+/// 把存储中的文件以流的形式复制到`dir`中的临时文件，不会把整个文件读入内存
+///
+/// ## 参数
+/// - `storage`: 文件所在的存储
+/// - `name`: 文件名
+/// - `dir`: 存放临时文件的目录
+pub async fn download<S: Storage + ?Sized>(storage: &S, name: &str, dir: &Path) -> io::Result<NamedTempFile> {
+    let temp_file = NamedTempFile::new_in(dir)?;
+    let mut writer = tokio::fs::File::from_std(temp_file.reopen()?);
+    let mut stream = storage.stream(name).await?;
+    while let Some(chunk) = stream.next().await {
+        writer.write_all(&chunk?).await?;
+    }
+    writer.flush().await?;
+    Ok(temp_file)
+}
+
+/// 经过临时文件把文件复制到`target`中，再从`source`中删除
+async fn copy_then_delete<S: Storage + ?Sized>(source: &S, name: &str, target: &dyn Storage) -> io::Result<()> {
+    let temp_file = download(source, name, &std::env::temp_dir()).await?;
+    target.put_file(name, temp_file).await?;
+    source.delete(name).await
+}
+
+/// 根据配置构建存储后端
+pub fn from_config(config: &Config) -> io::Result<Arc<dyn Storage>> {
+    match config.storage() {
+        StorageBackend::Local => {
+            let storage = LocalStorage::sharded(format!("{}/file", config.www_root()), config.shard_levels())?;
+            Ok(Arc::new(storage))
+        }
+        StorageBackend::S3 => {
+            let s3_config = config.s3().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "storage is S3 but [s3] is missing in config")
+            })?;
+            Ok(Arc::new(S3Storage::new(s3_config)?))
+        }
+    }
+}
+
+/// # trash_from_config
+///
+/// 根据配置构建回收站，与存储使用同一种后端。
+///
+/// 本地存储的回收站是`www_root/trash`；S3的回收站是前缀下的`trash/`，列出存储中的文件时不会包含它。
+pub fn trash_from_config(config: &Config) -> io::Result<Arc<dyn Storage>> {
+    match config.storage() {
+        StorageBackend::Local => {
+            let trash = LocalStorage::new(format!("{}/trash", config.www_root()))?;
+            Ok(Arc::new(trash))
+        }
+        StorageBackend::S3 => {
+            let mut s3_config = config.s3().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "storage is S3 but [s3] is missing in config")
+            })?;
+            s3_config.prefix.push_str("trash/");
+            // 回收站中的文件不能被直接访问
+            s3_config.redirect = false;
+            Ok(Arc::new(S3Storage::new(s3_config)?))
+        }
+    }
+}
@@ -0,0 +1,653 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row, TransactionBehavior};
+use serde_derive::Serialize;
+
+use crate::auth::{ApiToken, Scope};
+use crate::storage::Storage;
+use crate::util::get_time;
+
+/// 数据库被其他进程（例如命令行工具）锁住时最多等待的时间
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 只读连接的数量，访问文件时的查询分散到这些连接上，不必和写操作抢同一个锁
+const READERS: usize = 4;
+
+/// 建表语句，每次打开数据库时执行
+const SCHEMA: &str = "
+    -- 去重后每个文件被上传的次数
+    CREATE TABLE IF NOT EXISTS blobs (
+        name TEXT PRIMARY KEY,
+        refs INTEGER NOT NULL
+    );
+    -- 图片的感知哈希，以有符号整数保存64位的哈希
+    CREATE TABLE IF NOT EXISTS perceptual_hashes (
+        name TEXT PRIMARY KEY,
+        hash INTEGER NOT NULL
+    );
+    -- 存储中每个文件的上传记录
+    CREATE TABLE IF NOT EXISTS files (
+        name TEXT PRIMARY KEY,
+        original_name TEXT,
+        uploader TEXT,
+        uploaded_at INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        mime TEXT NOT NULL,
+        width INTEGER,
+        height INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS files_uploaded_at ON files (uploaded_at);
+";
+
+/// 在`SCHEMA`之后依次执行的升级语句，已经执行过的数量记录在`user_version`中
+///
+/// 只能在末尾追加，不能修改已有的语句。
+const MIGRATIONS: &[&str] = &[
+    // 文件的过期时间，Unix时间戳，NULL表示永不过期
+    "ALTER TABLE files ADD COLUMN expires_at INTEGER;
+     CREATE INDEX files_expires_at ON files (expires_at);",
+    // 文件剩余的访问次数，NULL表示不限制
+    "ALTER TABLE files ADD COLUMN views_left INTEGER;
+     -- 过期或访问次数用完而被删除的文件，之后访问时返回410而不是404
+     CREATE TABLE gone (name TEXT PRIMARY KEY, gone_at INTEGER NOT NULL);",
+    // 文件被移入回收站的时间，NULL表示不在回收站中
+    "ALTER TABLE files ADD COLUMN trashed_at INTEGER;
+     CREATE INDEX files_trashed_at ON files (trashed_at);",
+    // 每次上传生成的删除密钥的哈希，去重后同一个文件可能有多个
+    "CREATE TABLE delete_keys (key_hash TEXT PRIMARY KEY, name TEXT NOT NULL);
+     CREATE INDEX delete_keys_name ON delete_keys (name);",
+    // 上传文件时使用的token的名称，没有使用token时为NULL
+    "ALTER TABLE files ADD COLUMN token TEXT;
+     -- 通过命令行添加的token，只保存哈希，scopes是以逗号分隔的权限
+     CREATE TABLE tokens (
+         name TEXT PRIMARY KEY,
+         token_hash TEXT NOT NULL UNIQUE,
+         scopes TEXT NOT NULL,
+         expires_at INTEGER,
+         max_file_size INTEGER
+     );",
+];
+
+/// 查询`files`表时选出的列，顺序与`FileRecord::from_row`一致
+const FILE_COLUMNS: &str = "name, original_name, uploader, uploaded_at, size, mime, width, height, expires_at, views_left, trashed_at, token";
+
+/// # FileRecord
+///
+/// 一个文件的上传记录
+#[derive(Serialize, Debug, Clone)]
+pub struct FileRecord {
+    /// 存储中的文件名
+    pub name: String,
+    /// 上传时客户端提供的文件名
+    pub original_name: Option<String>,
+    /// 上传者的IP地址
+    pub uploader: Option<String>,
+    /// 上传时间，Unix时间戳，单位为秒
+    pub uploaded_at: u64,
+    /// 文件大小，单位为字节
+    pub size: u64,
+    pub mime: String,
+    /// 图片的宽度，不是图片时为`None`
+    pub width: Option<u32>,
+    /// 图片的高度，不是图片时为`None`
+    pub height: Option<u32>,
+    /// 过期时间，Unix时间戳，单位为秒，永不过期时为`None`
+    pub expires_at: Option<u64>,
+    /// 剩余的访问次数，不限制时为`None`
+    pub views_left: Option<u64>,
+    /// 移入回收站的时间，Unix时间戳，不在回收站中时为`None`
+    pub trashed_at: Option<u64>,
+    /// 上传时使用的token的名称，没有使用token时为`None`
+    pub token: Option<String>,
+}
+
+impl FileRecord {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            name: row.get(0)?,
+            original_name: row.get(1)?,
+            uploader: row.get(2)?,
+            uploaded_at: row.get(3)?,
+            size: row.get(4)?,
+            mime: row.get(5)?,
+            width: row.get(6)?,
+            height: row.get(7)?,
+            expires_at: row.get(8)?,
+            views_left: row.get(9)?,
+            trashed_at: row.get(10)?,
+            token: row.get(11)?,
+        })
+    }
+}
+
+/// 查询`tokens`表时选出的列，顺序与`token_from_row`一致
+const TOKEN_COLUMNS: &str = "name, scopes, expires_at, max_file_size";
+
+fn token_from_row(row: &Row) -> rusqlite::Result<ApiToken> {
+    let scopes: String = row.get(1)?;
+    Ok(ApiToken {
+        name: row.get(0)?,
+        // 不认识的权限直接忽略
+        scopes: scopes.split(',').filter_map(|scope| scope.parse().ok()).collect(),
+        expires_at: row.get(2)?,
+        max_file_size: row.get(3)?,
+    })
+}
+
+/// # MetaStore
+///
+/// 保存文件元数据的SQLite数据库，存放在`www_root/meta.db`。
+///
+/// 服务运行时也可以通过命令行工具访问。数据库操作会阻塞，所有方法都在阻塞线程池中执行。
+///
+/// 写操作共用一个连接；频繁的查询使用单独的只读连接，在WAL模式下可以同时进行。
+#[derive(Clone)]
+pub struct MetaStore {
+    conn: Arc<Mutex<Connection>>,
+    readers: Arc<Vec<Mutex<Connection>>>,
+    next_reader: Arc<AtomicUsize>,
+}
+
+impl MetaStore {
+    /// 打开数据库，不存在时创建
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut conn = Connection::open(path).map_err(io::Error::other)?;
+        conn.busy_timeout(BUSY_TIMEOUT).map_err(io::Error::other)?;
+        // WAL模式下读写互不阻塞
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(io::Error::other)?;
+        conn.execute_batch(SCHEMA).map_err(io::Error::other)?;
+        migrate(&mut conn).map_err(io::Error::other)?;
+        let readers = (0..READERS)
+            .map(|_| {
+                let reader = Connection::open_with_flags(
+                    path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )?;
+                reader.busy_timeout(BUSY_TIMEOUT)?;
+                Ok(Mutex::new(reader))
+            })
+            .collect::<rusqlite::Result<_>>()
+            .map_err(io::Error::other)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            readers: Arc::new(readers),
+            next_reader: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// 在阻塞线程池中执行数据库操作
+    async fn run<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .map_err(io::Error::other)?
+            .map_err(io::Error::other)
+    }
+
+    /// 在阻塞线程池中用一个只读连接执行查询
+    async fn read<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let readers = self.readers.clone();
+        let index = self.next_reader.fetch_add(1, Ordering::Relaxed) % readers.len();
+        tokio::task::spawn_blocking(move || f(&readers[index].lock().unwrap()))
+            .await
+            .map_err(io::Error::other)?
+            .map_err(io::Error::other)
+    }
+
+    /// 增加一次对文件`name`的引用，返回增加后的引用次数
+    pub async fn acquire(&self, name: &str) -> io::Result<u64> {
+        let name = name.to_string();
+        self.run(move |conn| {
+            conn.query_row(
+                "INSERT INTO blobs (name, refs) VALUES (?1, 1)
+                 ON CONFLICT (name) DO UPDATE SET refs = refs + 1
+                 RETURNING refs",
+                params![name],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    /// 减少一次对文件`name`的引用，返回剩余的引用次数
+    ///
+    /// 没有记录过引用的文件（例如未开启去重时上传的文件）视为只有一次引用，返回0。
+    pub async fn release(&self, name: &str) -> io::Result<u64> {
+        let name = name.to_string();
+        self.run(move |conn| {
+            let txn = conn.transaction()?;
+            let remaining: u64 = txn
+                .query_row(
+                    "UPDATE blobs SET refs = refs - 1 WHERE name = ?1 RETURNING refs",
+                    params![name],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .unwrap_or(0);
+            if remaining == 0 {
+                txn.execute("DELETE FROM blobs WHERE name = ?1", params![name])?;
+            }
+            txn.commit()?;
+            Ok(remaining)
+        })
+        .await
+    }
+
+    /// 记录图片`name`的感知哈希
+    pub async fn set_perceptual_hash(&self, name: &str, hash: u64) -> io::Result<()> {
+        let name = name.to_string();
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO perceptual_hashes (name, hash) VALUES (?1, ?2)",
+                params![name, hash as i64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// 所有图片的感知哈希
+    pub async fn perceptual_hashes(&self) -> io::Result<Vec<(String, u64)>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT name, hash FROM perceptual_hashes
+                 WHERE name NOT IN (SELECT name FROM files WHERE trashed_at IS NOT NULL)
+                 ORDER BY name",
+            )?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// 记录一个文件的上传信息
+    ///
+    /// 文件已有记录时（例如去重后再次上传）保留原来的记录。会过期或限制访问次数的上传不参与去重，
+    /// 因此共享的记录不会带有某一次上传的限制。文件在回收站中时，重新上传会让它离开回收站。
+    pub async fn insert_file(&self, record: FileRecord) -> io::Result<()> {
+        self.run(move |conn| {
+            conn.execute("DELETE FROM gone WHERE name = ?1", params![record.name])?;
+            conn.execute(
+                &format!(
+                    "INSERT INTO files ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+                     ON CONFLICT (name) DO UPDATE SET trashed_at = excluded.trashed_at",
+                    FILE_COLUMNS
+                ),
+                params![
+                    record.name,
+                    record.original_name,
+                    record.uploader,
+                    record.uploaded_at,
+                    record.size,
+                    record.mime,
+                    record.width,
+                    record.height,
+                    record.expires_at,
+                    record.views_left,
+                    record.trashed_at,
+                    record.token,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// 查询一个文件的上传记录
+    pub async fn file(&self, name: &str) -> io::Result<Option<FileRecord>> {
+        let name = name.to_string();
+        self.read(move |conn| {
+            conn.query_row(
+                &format!("SELECT {} FROM files WHERE name = ?1", FILE_COLUMNS),
+                params![name],
+                FileRecord::from_row,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    /// 按上传时间从新到旧列出文件，跳过前`offset`个，最多返回`limit`个
+    pub async fn list_files(&self, offset: u64, limit: u64) -> io::Result<Vec<FileRecord>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM files WHERE trashed_at IS NULL
+                 ORDER BY uploaded_at DESC, name LIMIT ?1 OFFSET ?2",
+                FILE_COLUMNS
+            ))?;
+            let rows = stmt.query_map(params![limit, offset], FileRecord::from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// 记录一次上传生成的删除密钥
+    pub async fn add_delete_key(&self, name: &str, key_hash: &str) -> io::Result<()> {
+        let (name, key_hash) = (name.to_string(), key_hash.to_string());
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO delete_keys (key_hash, name) VALUES (?1, ?2)",
+                params![key_hash, name],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// 使用文件`name`的一个删除密钥，密钥正确时把它作废并返回`true`
+    pub async fn take_delete_key(&self, name: &str, key_hash: &str) -> io::Result<bool> {
+        let (name, key_hash) = (name.to_string(), key_hash.to_string());
+        self.run(move |conn| {
+            let deleted = conn.execute(
+                "DELETE FROM delete_keys WHERE key_hash = ?1 AND name = ?2",
+                params![key_hash, name],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    /// 添加一个token，只保存它的哈希。同名的token已经存在时返回`false`
+    pub async fn add_token(&self, token: ApiToken, token_hash: &str) -> io::Result<bool> {
+        let token_hash = token_hash.to_string();
+        let scopes: Vec<&str> = token.scopes.iter().map(Scope::as_str).collect();
+        let scopes = scopes.join(",");
+        self.run(move |conn| {
+            let inserted = conn.execute(
+                "INSERT INTO tokens (name, token_hash, scopes, expires_at, max_file_size) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (name) DO NOTHING",
+                params![token.name, token_hash, scopes, token.expires_at, token.max_file_size],
+            )?;
+            Ok(inserted > 0)
+        })
+        .await
+    }
+
+    /// 按哈希查找token
+    pub async fn token(&self, token_hash: &str) -> io::Result<Option<ApiToken>> {
+        let token_hash = token_hash.to_string();
+        self.read(move |conn| {
+            conn.query_row(
+                &format!("SELECT {} FROM tokens WHERE token_hash = ?1", TOKEN_COLUMNS),
+                params![token_hash],
+                token_from_row,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    /// 所有添加到数据库中的token，按名称排序
+    pub async fn tokens(&self) -> io::Result<Vec<ApiToken>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare(&format!("SELECT {} FROM tokens ORDER BY name", TOKEN_COLUMNS))?;
+            let rows = stmt.query_map([], token_from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// 吊销名为`name`的token，token存在时返回`true`
+    pub async fn remove_token(&self, name: &str) -> io::Result<bool> {
+        let name = name.to_string();
+        self.run(move |conn| {
+            let deleted = conn.execute("DELETE FROM tokens WHERE name = ?1", params![name])?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    /// # take_view
+    ///
+    /// 消耗一次限制访问次数的文件`name`的访问次数，返回剩余的次数。
+    ///
+    /// 次数已经用完时返回`None`；不限制次数的文件不应调用。
+    pub async fn take_view(&self, name: &str) -> io::Result<Option<u64>> {
+        let name = name.to_string();
+        self.run(move |conn| {
+            conn.query_row(
+                "UPDATE files SET views_left = views_left - 1
+                 WHERE name = ?1 AND views_left > 0
+                 RETURNING views_left",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    /// 记录文件因为过期或访问次数用完而被删除
+    pub async fn mark_gone(&self, name: &str) -> io::Result<()> {
+        let name = name.to_string();
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO gone (name, gone_at) VALUES (?1, ?2)",
+                params![name, get_time()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// 文件是否因为过期或访问次数用完而被删除
+    pub async fn is_gone(&self, name: &str) -> io::Result<bool> {
+        let name = name.to_string();
+        self.read(move |conn| {
+            conn.query_row("SELECT 1 FROM gone WHERE name = ?1", params![name], |_| Ok(()))
+                .optional()
+                .map(|row| row.is_some())
+        })
+        .await
+    }
+
+    /// 在`now`之前过期的所有文件
+    pub async fn expired(&self, now: u64) -> io::Result<Vec<String>> {
+        self.run(move |conn| {
+            let mut stmt =
+                conn.prepare("SELECT name FROM files WHERE expires_at <= ?1 AND trashed_at IS NULL")?;
+            let rows = stmt.query_map(params![now], |row| row.get(0))?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// # trash
+    ///
+    /// 记录文件`name`在`now`时被移入回收站。
+    ///
+    /// 没有上传记录的文件会补充一条，大小为`size`，MIME类型根据扩展名猜测。
+    pub async fn trash(&self, name: &str, size: u64, now: u64) -> io::Result<()> {
+        let name = name.to_string();
+        let mime = new_mime_guess::from_path(&name).first_or_octet_stream().essence_str().to_string();
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO files (name, uploaded_at, size, mime, trashed_at) VALUES (?1, ?2, ?3, ?4, ?2)
+                 ON CONFLICT (name) DO UPDATE SET trashed_at = excluded.trashed_at",
+                params![name, now, size, mime],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// 回收站中的所有文件，最近删除的在前
+    pub async fn trashed(&self) -> io::Result<Vec<FileRecord>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM files WHERE trashed_at IS NOT NULL ORDER BY trashed_at DESC, name",
+                FILE_COLUMNS
+            ))?;
+            let rows = stmt.query_map([], FileRecord::from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// 在`time`之前移入回收站的文件
+    pub async fn trashed_before(&self, time: u64) -> io::Result<Vec<String>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare("SELECT name FROM files WHERE trashed_at < ?1")?;
+            let rows = stmt.query_map(params![time], |row| row.get(0))?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// 把文件移出回收站，文件不在回收站中时返回`false`
+    ///
+    /// 文件被删除时`release`已经清掉了它的引用，恢复后它重新被引用一次，
+    /// 否则去重后再次上传相同的文件时，一次删除就会删掉两次上传共用的文件。
+    pub async fn restore(&self, name: &str) -> io::Result<bool> {
+        let name = name.to_string();
+        self.run(move |conn| {
+            let txn = conn.transaction()?;
+            let updated = txn.execute(
+                "UPDATE files SET trashed_at = NULL WHERE name = ?1 AND trashed_at IS NOT NULL",
+                params![name],
+            )?;
+            if updated > 0 {
+                txn.execute(
+                    "INSERT INTO blobs (name, refs) VALUES (?1, 1)
+                     ON CONFLICT (name) DO UPDATE SET refs = refs + 1",
+                    params![name],
+                )?;
+            }
+            txn.commit()?;
+            Ok(updated > 0)
+        })
+        .await
+    }
+
+    /// 文件从回收站中彻底删除后，删除它的所有元数据
+    ///
+    /// 文件已经被重新上传而离开回收站时什么也不做，返回`false`。
+    pub async fn purge(&self, name: &str) -> io::Result<bool> {
+        let name = name.to_string();
+        self.run(move |conn| {
+            let txn = conn.transaction()?;
+            let deleted = txn.execute(
+                "DELETE FROM files WHERE name = ?1 AND trashed_at IS NOT NULL",
+                params![name],
+            )?;
+            if deleted > 0 {
+                txn.execute("DELETE FROM perceptual_hashes WHERE name = ?1", params![name])?;
+                txn.execute("DELETE FROM blobs WHERE name = ?1", params![name])?;
+                txn.execute("DELETE FROM delete_keys WHERE name = ?1", params![name])?;
+            }
+            txn.commit()?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    /// 记录中的文件总数和总大小（字节）
+    pub async fn totals(&self) -> io::Result<(u64, u64)> {
+        self.run(|conn| {
+            let sql = "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM files WHERE trashed_at IS NULL";
+            conn.query_row(sql, [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+        })
+        .await
+    }
+
+    /// # backfill
+    ///
+    /// 为存储中还没有上传记录的文件（例如数据库出现之前上传的文件）补充记录，返回补充的数量。
+    ///
+    /// 补充的记录没有原始文件名和上传者，上传时间取文件的修改时间，MIME类型根据扩展名猜测。
+    pub async fn backfill(&self, storage: &dyn Storage) -> io::Result<usize> {
+        let known: HashSet<String> = self
+            .run(|conn| {
+                let mut stmt = conn.prepare("SELECT name FROM files")?;
+                let rows = stmt.query_map([], |row| row.get(0))?;
+                rows.collect()
+            })
+            .await?;
+        let mut count = 0;
+        for name in storage.list().await? {
+            if known.contains(&name) {
+                continue;
+            }
+            let stat = storage.stat(&name).await?;
+            let uploaded_at = stat
+                .modified
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or_else(get_time, |duration| duration.as_secs());
+            let mime = new_mime_guess::from_path(&name).first_or_octet_stream();
+            self.insert_file(FileRecord {
+                mime: mime.essence_str().to_string(),
+                name,
+                original_name: None,
+                uploader: None,
+                uploaded_at,
+                size: stat.size,
+                width: None,
+                height: None,
+                expires_at: None,
+                views_left: None,
+                trashed_at: None,
+                token: None,
+            })
+            .await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// 文件被删除后，删除它的所有元数据
+    pub async fn forget(&self, name: &str) -> io::Result<()> {
+        let name = name.to_string();
+        self.run(move |conn| {
+            conn.execute("DELETE FROM perceptual_hashes WHERE name = ?1", params![name])?;
+            conn.execute("DELETE FROM files WHERE name = ?1", params![name])?;
+            conn.execute("DELETE FROM blobs WHERE name = ?1", params![name])?;
+            conn.execute("DELETE FROM delete_keys WHERE name = ?1", params![name])?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// 清空所有文件的元数据，token不受影响
+    pub async fn clear(&self) -> io::Result<()> {
+        self.run(|conn| {
+            conn.execute_batch(
+                "DELETE FROM blobs; DELETE FROM perceptual_hashes; DELETE FROM files; DELETE FROM gone;
+                 DELETE FROM delete_keys;",
+            )
+        })
+        .await
+    }
+}
+
+/// 执行还没有执行过的升级语句
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    // 立即加写锁，避免服务和命令行工具同时升级
+    let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: usize = txn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for migration in MIGRATIONS.iter().skip(version) {
+        txn.execute_batch(migration)?;
+    }
+    txn.pragma_update(None, "user_version", version.max(MIGRATIONS.len()))?;
+    txn.commit()
+}
@@ -0,0 +1,286 @@
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader, Writer,
+};
+
+/// 连同内容一起删除的元素，它们可以执行脚本或嵌入外部文档
+const FORBIDDEN_ELEMENTS: [&str; 9] = [
+    "script",
+    "foreignobject",
+    "iframe",
+    "embed",
+    "object",
+    "handler",
+    "listener",
+    "audio",
+    "video",
+];
+
+/// 可以修改其他属性的动画元素，修改链接时会被删除
+const ANIMATION_ELEMENTS: [&str; 4] = ["set", "animate", "animatemotion", "animatetransform"];
+
+/// 链接类的属性，只允许指向文档内部或内嵌的图片
+const LINK_ATTRIBUTES: [&str; 3] = ["href", "src", "action"];
+
+/// # sanitize
+///
+/// 清理SVG中可以执行脚本或引用外部资源的内容：
+///
+/// - 删除`script`、`foreignObject`等元素及其内容
+/// - 删除`on*`事件属性，以及值中包含`javascript:`的属性
+/// - 删除指向外部的链接和`url()`引用，只保留`#id`和内嵌的图片
+/// - 删除DOCTYPE和处理指令，避免实体展开和外部样式表
+///
+/// SVG不是合法的XML时返回错误。
+pub fn sanitize(data: &[u8]) -> quick_xml::Result<Vec<u8>> {
+    let mut reader = Reader::from_reader(data);
+    let mut writer = Writer::new(Vec::with_capacity(data.len()));
+    let mut buf = Vec::new();
+    // 正在删除的元素的嵌套深度，大于0时丢弃所有内容
+    let mut skip_depth = 0usize;
+    // 当前是否在`style`元素中
+    let mut in_style = false;
+
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Eof => break,
+            Event::Start(e) => {
+                if skip_depth > 0 || is_forbidden(&e)? {
+                    skip_depth += 1;
+                } else {
+                    in_style = local_name(&e) == "style";
+                    writer.write_event(Event::Start(clean(&e)?))?;
+                }
+            }
+            Event::End(e) => {
+                if skip_depth > 0 {
+                    skip_depth -= 1;
+                } else {
+                    in_style = false;
+                    writer.write_event(Event::End(e))?;
+                }
+            }
+            Event::Empty(e) => {
+                if skip_depth == 0 && !is_forbidden(&e)? {
+                    writer.write_event(Event::Empty(clean(&e)?))?;
+                }
+            }
+            Event::Text(e) => {
+                // 样式中的实体无法解析时，同样视为危险
+                let dangerous = in_style && e.unescape().map_or(true, |css| has_external_reference(&css));
+                if skip_depth == 0 && !dangerous {
+                    writer.write_event(Event::Text(e))?;
+                }
+            }
+            Event::CData(e) => {
+                if skip_depth == 0 && !(in_style && has_external_reference(&String::from_utf8_lossy(&e))) {
+                    writer.write_event(Event::CData(e))?;
+                }
+            }
+            Event::DocType(_) | Event::PI(_) => {}
+            event => {
+                if skip_depth == 0 {
+                    writer.write_event(event)?;
+                }
+            }
+        }
+        buf.clear();
+    }
+    Ok(writer.into_inner())
+}
+
+/// 元素不含命名空间前缀的小写名称
+fn local_name(e: &BytesStart) -> String {
+    String::from_utf8_lossy(e.local_name().as_ref()).to_lowercase()
+}
+
+/// 元素是否需要连同内容一起删除
+fn is_forbidden(e: &BytesStart) -> quick_xml::Result<bool> {
+    let name = local_name(e);
+    if FORBIDDEN_ELEMENTS.contains(&name.as_str()) {
+        return Ok(true);
+    }
+    if ANIMATION_ELEMENTS.contains(&name.as_str()) {
+        for attr in e.attributes() {
+            let attr = attr?;
+            if attr.key.local_name().as_ref().eq_ignore_ascii_case(b"attributeName")
+                && normalize(&attr.unescape_value()?).ends_with("href")
+            {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// 去掉危险属性后的元素
+fn clean<'a>(e: &BytesStart<'a>) -> quick_xml::Result<BytesStart<'a>> {
+    let mut cleaned = e.clone();
+    cleaned.clear_attributes();
+    for attr in e.attributes() {
+        let attr = attr?;
+        let name = String::from_utf8_lossy(attr.key.local_name().as_ref()).to_lowercase();
+        // 值中的实体无法解析时，同样视为危险
+        let value = match attr.unescape_value() {
+            Ok(value) => normalize(&value),
+            Err(_) => continue,
+        };
+        let safe = !name.starts_with("on")
+            && !value.contains("javascript:")
+            && !value.contains("vbscript:")
+            && !has_external_reference(&value)
+            && (!LINK_ATTRIBUTES.contains(&name.as_str()) || is_local_reference(&value));
+        if safe {
+            cleaned.push_attribute(attr);
+        }
+    }
+    Ok(cleaned)
+}
+
+/// 去掉空白和控制字符并转为小写，避免`java&#9;script:`这类写法绕过检查
+fn normalize(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// 是否是指向文档内部或内嵌图片的引用
+fn is_local_reference(value: &str) -> bool {
+    value.starts_with('#')
+        || (value.starts_with("data:image/") && !value.starts_with("data:image/svg"))
+}
+
+/// CSS中是否有引用外部资源的写法
+///
+/// CSS转义（如`u\rl(`、`\75rl(`）会被浏览器还原成`url(`，无法可靠地检查，一律视为危险。
+fn has_external_reference(css: &str) -> bool {
+    let css = normalize(css);
+    if css.contains('\\') || css.contains("@import") || css.contains("expression(") || css.contains("-moz-binding") {
+        return true;
+    }
+    css.split("url(").skip(1).any(|rest| {
+        let target = rest.trim_start_matches(['"', '\'']);
+        !is_local_reference(target)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitized(svg: &str) -> String {
+        String::from_utf8(sanitize(svg.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn keeps_plain_drawings() {
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10"><defs><linearGradient id="g"/></defs><rect width="10" height="10" fill="url(#g)"/><use href="#g"/></svg>"##;
+        assert_eq!(sanitized(svg), svg);
+    }
+
+    #[test]
+    fn removes_scripts() {
+        let svg = sanitized(
+            r#"<svg><script>alert(1)</script><SCRIPT type="text/javascript"><![CDATA[alert(2)]]></SCRIPT><svg:script xmlns:svg="http://www.w3.org/2000/svg">alert(3)</svg:script><script href="x.js"/><rect/></svg>"#,
+        );
+        assert_eq!(svg, "<svg><rect/></svg>");
+    }
+
+    #[test]
+    fn removes_event_attributes() {
+        let svg = sanitized(r#"<svg onload="alert(1)"><rect ONCLICK="alert(2)" width="1" onMouseOver = 'alert(3)'/><a xlink:onfocus="alert(4)"/></svg>"#);
+        assert_eq!(svg, r#"<svg><rect width="1"/><a/></svg>"#);
+    }
+
+    #[test]
+    fn removes_script_links() {
+        for href in [
+            "javascript:alert(1)",
+            "JavaScript:alert(1)",
+            " javascript:alert(1)",
+            "java\tscript:alert(1)",
+            "java&#9;script:alert(1)",
+            "java&#x0A;script:alert(1)",
+            "&#106;avascript:alert(1)",
+            "&#x6A;&#x61;&#x76;&#x61;&#x73;&#x63;&#x72;&#x69;&#x70;&#x74;&#x3A;alert(1)",
+            "vbscript:msgbox(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "data:text/html;base64,PHNjcmlwdD5hbGVydCgxKTwvc2NyaXB0Pg==",
+            "DATA:image/svg+xml;base64,PHN2Zy8+",
+            " data:image/svg+xml,<svg onload='alert(1)'/>",
+            "https://example.com/",
+            "//example.com/x.png",
+        ] {
+            for attribute in ["href", "xlink:href", "src"] {
+                let svg = format!(r#"<svg><a {}="{}"><text>x</text></a></svg>"#, attribute, href.replace('<', "&lt;"));
+                assert_eq!(sanitized(&svg), "<svg><a><text>x</text></a></svg>", "{}", svg);
+            }
+        }
+    }
+
+    #[test]
+    fn removes_script_values_in_other_attributes() {
+        let svg = sanitized(r#"<svg><rect fill="javascript:alert(1)" style="background:url(https://example.com/x)" filter="url(&#x20;http://example.com/f)"/></svg>"#);
+        assert_eq!(svg, "<svg><rect/></svg>");
+    }
+
+    #[test]
+    fn keeps_local_and_embedded_image_links() {
+        let svg = r##"<svg><use xlink:href="#icon"/><image href="data:image/png;base64,iVBORw0KGgo="/></svg>"##;
+        assert_eq!(sanitized(svg), svg);
+    }
+
+    #[test]
+    fn removes_link_animations() {
+        let svg = sanitized(
+            r#"<svg><a><set attributeName="href" to="javascript:alert(1)"/><animate attributeName="xlink:href" values="javascript:alert(1)"></animate><animate attributeName="opacity" to="0"/></a></svg>"#,
+        );
+        assert_eq!(svg, r#"<svg><a><animate attributeName="opacity" to="0"/></a></svg>"#);
+    }
+
+    #[test]
+    fn removes_embedded_documents() {
+        let svg = sanitized(
+            r#"<svg><foreignObject width="10" height="10"><body xmlns="http://www.w3.org/1999/xhtml"><img src="x" onerror="alert(1)"/></body></foreignObject><FOREIGNOBJECT/><iframe src="https://example.com"/><embed src="x.swf"/><object data="x"></object><rect/></svg>"#,
+        );
+        assert_eq!(svg, "<svg><rect/></svg>");
+    }
+
+    #[test]
+    fn removes_external_styles() {
+        for css in [
+            "@import url(https://example.com/x.css);",
+            "@IMPORT 'https://example.com/x.css';",
+            "rect { fill: url(https://example.com/x.svg#g) }",
+            "rect { background: url( 'javascript:alert(1)' ) }",
+            "rect { background: u\\rl(x) }",
+            "rect { background: \\75rl(https://example.com/x) }",
+            "@\\69mport 'https://example.com/x.css';",
+            "rect { width: expression(alert(1)) }",
+            "rect { -moz-binding: url(#x) }",
+        ] {
+            let svg = sanitized(&format!("<svg><style>{}</style><rect/></svg>", css));
+            assert_eq!(svg, "<svg><style></style><rect/></svg>", "{}", css);
+            let svg = sanitized(&format!("<svg><style><![CDATA[{}]]></style><rect/></svg>", css));
+            assert_eq!(svg, "<svg><style></style><rect/></svg>", "{}", css);
+        }
+        let svg = "<svg><style>rect { fill: url(#g); stroke: red }</style><rect/></svg>";
+        assert_eq!(sanitized(svg), svg);
+    }
+
+    #[test]
+    fn removes_doctype_and_processing_instructions() {
+        let svg = sanitized(
+            r#"<?xml-stylesheet href="https://example.com/x.css"?><!DOCTYPE svg [<!ENTITY x "javascript:alert(1)">]><svg><rect/></svg>"#,
+        );
+        assert_eq!(svg, "<svg><rect/></svg>");
+    }
+
+    #[test]
+    fn rejects_malformed_svg() {
+        assert!(sanitize(b"<svg><rect></svg>").is_err());
+        assert!(sanitize(br#"<svg><a href="&x;"/></svg>"#).map_or(true, |svg| !String::from_utf8_lossy(&svg).contains("href")));
+    }
+}
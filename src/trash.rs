@@ -0,0 +1,181 @@
+use std::{io, sync::Arc};
+
+use crate::storage::Storage;
+use crate::store::{FileRecord, MetaStore};
+use crate::util::get_time;
+
+/// # Trash
+///
+/// 回收站：删除的文件先移到这里，保留`retention`秒之后才被彻底删除，在此之前可以恢复。
+///
+/// 文件是否在回收站中、何时被移入都记录在元数据库中。
+#[derive(Clone)]
+pub struct Trash {
+    storage: Arc<dyn Storage>,
+    trash: Arc<dyn Storage>,
+    store: MetaStore,
+    retention: u64,
+}
+
+impl Trash {
+    /// ## 参数
+    /// - `storage`: 文件存储
+    /// - `trash`: 存放回收站中文件的存储
+    /// - `store`: 元数据库
+    /// - `retention`: 文件在回收站中保留的时间，单位为秒
+    pub fn new(storage: Arc<dyn Storage>, trash: Arc<dyn Storage>, store: MetaStore, retention: u64) -> Self {
+        Self {
+            storage,
+            trash,
+            store,
+            retention,
+        }
+    }
+
+    /// 把文件从存储移入回收站，返回文件的大小
+    pub async fn put(&self, name: &str) -> io::Result<u64> {
+        let size = self.storage.stat(name).await?.size;
+        self.storage.move_to(name, self.trash.as_ref()).await?;
+        self.store.trash(name, size, get_time()).await?;
+        Ok(size)
+    }
+
+    /// # restore
+    ///
+    /// 把文件从回收站移回存储，返回文件的大小；文件不在回收站中时返回`NotFound`错误。
+    ///
+    /// 文件在此期间被重新上传时，直接丢弃回收站中的副本，返回`None`。
+    pub async fn restore(&self, name: &str) -> io::Result<Option<u64>> {
+        let in_trash = self
+            .store
+            .file(name)
+            .await?
+            .is_some_and(|record| record.trashed_at.is_some());
+        if !in_trash {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "file is not in trash"));
+        }
+        let restored = match self.storage.stat(name).await {
+            Ok(_) => {
+                self.trash.delete(name).await?;
+                None
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let size = self.trash.stat(name).await?.size;
+                self.trash.move_to(name, self.storage.as_ref()).await?;
+                Some(size)
+            }
+            Err(e) => return Err(e),
+        };
+        self.store.restore(name).await?;
+        Ok(restored)
+    }
+
+    /// 彻底删除回收站中的文件
+    pub async fn purge(&self, name: &str) -> io::Result<()> {
+        match self.trash.delete(name).await {
+            // 回收站中的副本已经不在了，仍然要清理记录
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        self.store.purge(name).await?;
+        Ok(())
+    }
+
+    /// 彻底删除在回收站中超过保留时间的文件，返回删除的文件名
+    pub async fn purge_expired(&self) -> io::Result<Vec<String>> {
+        let deadline = get_time().saturating_sub(self.retention);
+        let names = self.store.trashed_before(deadline).await?;
+        for name in &names {
+            self.purge(name).await?;
+        }
+        Ok(names)
+    }
+
+    /// 彻底删除回收站中的所有文件，返回删除的数量
+    pub async fn empty(&self) -> io::Result<usize> {
+        let records = self.store.trashed().await?;
+        for record in &records {
+            self.purge(&record.name).await?;
+        }
+        Ok(records.len())
+    }
+
+    /// 回收站中的所有文件，最近删除的在前
+    pub async fn list(&self) -> io::Result<Vec<FileRecord>> {
+        self.store.trashed().await
+    }
+
+    /// 文件在回收站中保留的时间，单位为秒
+    pub fn retention(&self) -> u64 {
+        self.retention
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    const NAME: &str = "abcdef.png";
+
+    fn record() -> FileRecord {
+        FileRecord {
+            name: NAME.to_string(),
+            original_name: None,
+            uploader: None,
+            uploaded_at: get_time(),
+            size: 1,
+            mime: "image/png".to_string(),
+            width: None,
+            height: None,
+            expires_at: None,
+            views_left: None,
+            trashed_at: None,
+            token: None,
+        }
+    }
+
+    /// 与上传接口一样，去重时文件已经存在就只增加引用
+    async fn upload(trash: &Trash) {
+        if trash.store.acquire(NAME).await.unwrap() == 1 || trash.storage.stat(NAME).await.is_err() {
+            let mut file = NamedTempFile::new().unwrap();
+            io::Write::write_all(&mut file, b"x").unwrap();
+            trash.storage.put_file(NAME, file).await.unwrap();
+        }
+        trash.store.insert_file(record()).await.unwrap();
+    }
+
+    /// 与删除接口一样，最后一次引用被删除时才把文件移入回收站
+    async fn delete(trash: &Trash) -> u64 {
+        let remaining = trash.store.release(NAME).await.unwrap();
+        if remaining == 0 {
+            trash.put(NAME).await.unwrap();
+        }
+        remaining
+    }
+
+    #[actix_web::test]
+    async fn restored_file_keeps_its_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(LocalStorage::new(dir.path().join("file")).unwrap());
+        let trash_storage = Arc::new(LocalStorage::new(dir.path().join("trash")).unwrap());
+        let store = MetaStore::open(dir.path().join("meta.db")).unwrap();
+        let trash = Trash::new(storage.clone(), trash_storage, store, 3600);
+
+        upload(&trash).await;
+        assert_eq!(delete(&trash).await, 0);
+        assert!(storage.stat(NAME).await.is_err());
+
+        assert_eq!(trash.restore(NAME).await.unwrap(), Some(1));
+        upload(&trash).await;
+        assert_eq!(delete(&trash).await, 1);
+        assert!(storage.stat(NAME).await.is_ok());
+        assert!(trash.list().await.unwrap().is_empty());
+
+        assert_eq!(delete(&trash).await, 0);
+        assert!(storage.stat(NAME).await.is_err());
+        assert_eq!(trash.list().await.unwrap().len(), 1);
+    }
+}
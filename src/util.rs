@@ -1,11 +1,13 @@
 use std::{
+    io,
     time::SystemTime,
-    fs,
-    path::Path,
 };
 
+use rand::RngCore;
 use sha2::{Digest, Sha256};
 
+use crate::storage::Storage;
+
 /// # get_time
 /// 
 /// 用于获取系统时间。
@@ -55,39 +57,12 @@ pub fn format_file_size(size: usize) -> String {
     format!("{:.1} {}", size, units[unit_index])
 }
 
-pub fn calculate_total_size(directory_path: &str) -> u64 {
-    let path = Path::new(directory_path);
-
-    if !path.is_dir() {
-        panic!("Path {} is not a directory!", directory_path);
-    }
-
-    let mut total_size = 0;
-
-    for entry in fs::read_dir(path).unwrap() {
-        let entry = entry.unwrap();
-        let metadata = entry.metadata().unwrap();
-
-        if metadata.is_file() {
-            total_size += metadata.len();
-        } else if metadata.is_dir() {
-            // 如果你想要递归计算子目录的文件大小，可以在这里调用递归函数
-            // 例如：total_size += calculate_total_size(entry.path().to_str().unwrap())?;
-        }
-    }
-
-    total_size
-}
-
-pub fn get_file_count(directory_path: &str) -> usize {
-    let path = Path::new(directory_path);
-
-    if !path.is_dir() {
-        panic!("Path {} is not a directory!", directory_path);
-    }
-
-    let count = fs::read_dir(path).unwrap().count();
-    count
+/// # 统计存储中的文件数和总体积
+///
+/// 只列一次目录，大小来自列出的结果，返回`(文件数, 总字节数)`。
+pub async fn scan_storage(storage: &dyn Storage) -> io::Result<(usize, u64)> {
+    let files = storage.list_with_sizes().await?;
+    Ok((files.len(), files.iter().map(|(_, size)| size).sum()))
 }
 
 pub fn get_str_sha256(input: &str) -> String {
@@ -95,4 +70,13 @@ pub fn get_str_sha256(input: &str) -> String {
     hasher.update(input);
     let result = hasher.finalize();
     format!("{:x}", result)
-}
\ No newline at end of file
+}
+
+/// # generate_key
+///
+/// 生成一个随机的密钥，128位，以16进制输出。
+pub fn generate_key() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}